@@ -0,0 +1,182 @@
+//! Album cover images written next to the songs on a destination, for players
+//! that look for `folder.jpg` rather than reading embedded art.
+
+use crate::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardVisualKey, Visual};
+use symphonia::core::probe::Hint;
+
+/// Stems of image files next to the songs that are taken as the album cover,
+/// in order of preference.
+const SIDECARS: [&str; 4] = ["cover", "folder", "front", "album"];
+
+/// Where a cover image comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Image {
+    /// An image file in the album's source folder.
+    File(PathBuf),
+    /// Art embedded in one of the album's songs.
+    Embedded { data: Vec<u8>, png: bool },
+}
+
+impl Image {
+    fn is_png(&self) -> bool {
+        match self {
+            Image::File(path) => path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("png")),
+            Image::Embedded { png, .. } => *png,
+        }
+    }
+}
+
+/// A cover image to be written into an album folder on a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cover {
+    /// The album folder, relative to the destination.
+    pub folder: String,
+    pub file_name: String,
+    pub image: Image,
+}
+
+impl Cover {
+    /// A cover named `file_name` in `folder`. PNG images get a `.png`
+    /// extension whatever `file_name` says, so players don't misread them.
+    pub fn new(folder: String, file_name: &str, image: Image) -> Cover {
+        let mut name = PathBuf::from(file_name);
+        if image.is_png() {
+            name.set_extension("png");
+        }
+        Cover {
+            folder,
+            file_name: name.to_string_lossy().into_owned(),
+            image,
+        }
+    }
+
+    /// The cover's path relative to the destination.
+    pub fn path(&self) -> String {
+        if self.folder.is_empty() {
+            self.file_name.clone()
+        } else {
+            format!("{}/{}", self.folder, self.file_name)
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match &self.image {
+            Image::File(path) => fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            Image::Embedded { data, .. } => data.len() as u64,
+        }
+    }
+}
+
+/// The cover for an album whose songs are in `folder`: a sidecar image if
+/// there is one, otherwise the front cover embedded in the first of `songs`
+/// that has any art.
+pub fn find(folder: &Path, songs: &[&Path]) -> Option<Image> {
+    sidecar(folder).or_else(|| songs.iter().find_map(|song| embedded(song)))
+}
+
+fn sidecar(folder: &Path) -> Option<Image> {
+    let images: Vec<PathBuf> = fs::read_dir(folder)
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| ["jpg", "jpeg", "png"].contains(&e.to_ascii_lowercase().as_str()))
+        })
+        .collect();
+
+    SIDECARS.iter().find_map(|wanted| {
+        images
+            .iter()
+            .find(|path| {
+                path.file_stem()
+                    .and_then(|s| s.to_str())
+                    .is_some_and(|s| s.eq_ignore_ascii_case(wanted))
+            })
+            .map(|path| Image::File(path.clone()))
+    })
+}
+
+/// The front cover embedded in `song`, or failing that its first picture.
+fn embedded(song: &Path) -> Option<Image> {
+    let stream = MediaSourceStream::new(Box::new(File::open(song).ok()?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = song.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .ok()?;
+
+    // ID3v2 tags are read while probing; other containers carry their own.
+    let mut visuals: Vec<Visual> = Vec::new();
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        visuals.extend_from_slice(revision.visuals());
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        visuals.extend_from_slice(revision.visuals());
+    }
+
+    let visual = visuals
+        .iter()
+        .find(|v| v.usage == Some(StandardVisualKey::FrontCover))
+        .or_else(|| visuals.first())?;
+    Some(Image::Embedded {
+        data: visual.data.to_vec(),
+        png: visual.media_type == "image/png",
+    })
+}
+
+/// Writes `covers` into a fresh temporary directory named after `target`,
+/// laid out as on the destination, and returns the directory.
+pub fn write_temp(target: &str, covers: &[Cover]) -> Result<PathBuf, Error> {
+    let dir = std::env::temp_dir().join(format!(
+        "shittysync-covers-{}",
+        filenamify::filenamify(target)
+    ));
+    match fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    for cover in covers {
+        let path = dir.join(cover.path());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &cover.image {
+            Image::File(source) => fs::copy(source, &path).map(|_| ())?,
+            Image::Embedded { data, .. } => fs::write(&path, data)?,
+        }
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_sidecars_by_name() {
+        let dir = std::env::temp_dir().join(format!("shittysync-art-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["back.jpg", "Folder.PNG", "cover.txt"] {
+            fs::write(dir.join(name), "image").unwrap();
+        }
+
+        let image = find(&dir, &[]).unwrap();
+        assert_eq!(image, Image::File(dir.join("Folder.PNG")));
+
+        let cover = Cover::new("Artist/Album".into(), "folder.jpg", image);
+        assert_eq!(cover.path(), "Artist/Album/folder.png");
+        assert_eq!(cover.size(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Housekeeping for the transcode cache folders written by
+//! [`Transcoder`](crate::transcode::Transcoder).
+//!
+//! Everything the transcoder writes goes in a [`TRANSCODES`] folder inside
+//! the configured cache folder, so that housekeeping never touches anything
+//! else that shares it, such as the rest of an MTP target's workspace. In
+//! there, transcodes live in two-character shard folders named after the
+//! start of their key. Every cache hit bumps the file's modification time, which is
+//! what least-recently-used eviction goes by.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The folder inside a cache folder that holds the transcodes.
+pub const TRANSCODES: &str = "transcodes";
+
+/// The folder, next to the shards, that transcodes are staged in before
+/// being synced.
+pub const STAGING: &str = "staging";
+
+/// A single cached transcode.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub used: SystemTime,
+}
+
+/// How many transcodes a cache holds, or how many an operation removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl CacheStats {
+    fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+    }
+}
+
+/// A transcode cache folder.
+pub struct Cache {
+    /// The [`TRANSCODES`] folder inside it.
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// The cache in the configured cache folder `dir`.
+    pub fn new(dir: impl AsRef<Path>) -> Cache {
+        Cache {
+            dir: dir.as_ref().join(TRANSCODES),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Every cached transcode, least recently used first. A missing folder is
+    /// an empty cache.
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        for shard in self.shards()? {
+            for file in fs::read_dir(&shard)? {
+                let file = file?;
+                let meta = file.metadata()?;
+                if meta.is_file() {
+                    entries.push(CacheEntry {
+                        path: file.path(),
+                        size: meta.len(),
+                        used: meta.modified()?,
+                    });
+                }
+            }
+        }
+        entries.sort_by_key(|e| e.used);
+        Ok(entries)
+    }
+
+    pub fn stats(&self) -> io::Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        for entry in self.entries()? {
+            stats.add(entry.size);
+        }
+        Ok(stats)
+    }
+
+    /// Evicts the least recently used transcodes until the cache holds at most
+    /// `max_bytes`, returning what was removed.
+    pub fn prune(&self, max_bytes: u64) -> io::Result<CacheStats> {
+        let entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|e| e.size).sum();
+        let mut removed = CacheStats::default();
+        for entry in entries {
+            if total <= max_bytes {
+                break;
+            }
+            fs::remove_file(&entry.path)?;
+            total -= entry.size;
+            removed.add(entry.size);
+        }
+        Ok(removed)
+    }
+
+    /// Removes every transcode and the staging folder, returning what was
+    /// removed. Anything else in the cache folder is left alone.
+    pub fn clear(&self) -> io::Result<CacheStats> {
+        let removed = self.stats()?;
+        for shard in self.shards()? {
+            fs::remove_dir_all(shard)?;
+        }
+        match fs::remove_dir_all(self.dir.join(STAGING)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        Ok(removed)
+    }
+
+    fn shards(&self) -> io::Result<Vec<PathBuf>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut shards = Vec::new();
+        for entry in dir {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && is_shard(&entry.file_name().to_string_lossy()) {
+                shards.push(entry.path());
+            }
+        }
+        Ok(shards)
+    }
+}
+
+/// Marks a cached transcode as just used.
+pub fn touch(path: &Path) -> io::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+fn is_shard(name: &str) -> bool {
+    name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parses a size such as `512M`, `20G` or `1.5T` (binary units, case
+/// insensitive, an optional trailing `B`) into bytes.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_ascii_uppercase();
+    let s = s.strip_suffix("IB").or_else(|| s.strip_suffix('B')).unwrap_or(&s);
+    let (number, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    let number: f64 = number.trim().parse().ok()?;
+    (number >= 0.0).then(|| (number * (1u64 << shift) as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("512 MiB"), Some(512 << 20));
+        assert_eq!(parse_size("1.5k"), Some(1536));
+        assert_eq!(parse_size("lots"), None);
+        assert_eq!(parse_size("-1G"), None);
+    }
+
+    #[test]
+    fn prunes_least_recently_used_first() {
+        let dir = std::env::temp_dir().join(format!("shittysync-cache-{}", std::process::id()));
+        let shard = dir.join(TRANSCODES).join("ab");
+        let staging = dir.join(TRANSCODES).join(STAGING);
+        let unrelated = dir.join("cd");
+        fs::create_dir_all(&shard).unwrap();
+        fs::create_dir_all(&staging).unwrap();
+        fs::create_dir_all(&unrelated).unwrap();
+
+        let old = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for name in ["old.m4a", "new.m4a"] {
+            fs::write(shard.join(name), [0; 10]).unwrap();
+        }
+        File::options()
+            .write(true)
+            .open(shard.join("old.m4a"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let cache = Cache::new(&dir);
+        assert_eq!(cache.stats().unwrap(), CacheStats { files: 2, bytes: 20 });
+        assert_eq!(cache.prune(15).unwrap(), CacheStats { files: 1, bytes: 10 });
+        assert!(shard.join("new.m4a").exists());
+        assert!(!shard.join("old.m4a").exists());
+
+        cache.clear().unwrap();
+        assert!(!shard.exists() && !staging.exists());
+        assert!(unrelated.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 /// Syncs Swinsian playlists to various destinations.
@@ -6,22 +6,119 @@ use std::path::PathBuf;
 #[command(version, about)]
 pub struct Args {
     /// Path to the configuration file.
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     pub config: PathBuf,
 
-    /// Sync playlists to the DJ deck and re-index the BluOS library.
-    #[arg(short, long)]
-    pub deck: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Sync playlists to the named targets.
+    Sync {
+        /// Names of the `[[target]]` entries to sync, in order.
+        #[arg(required_unless_present = "all")]
+        targets: Vec<String>,
+
+        /// Sync every configured target.
+        #[arg(long, conflicts_with = "targets")]
+        all: bool,
+
+        /// Print what would be copied, skipped, transcoded, uploaded or
+        /// written, without touching any destination.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// With `--dry-run`, list every file rather than just totals.
+        #[arg(long, requires = "dry_run")]
+        files: bool,
+
+        /// Delete files from MTP devices that no selected playlist wants any
+        /// more.
+        #[arg(long)]
+        prune: bool,
+
+        /// Ignore the recorded sync state and inspect every destination.
+        #[arg(long)]
+        rescan: bool,
+    },
+
+    /// Keep running, syncing the targets whose playlists change in Swinsian
+    /// and those on MTP devices or disks when they're connected. The config
+    /// is reloaded whenever it's edited.
+    Daemon {
+        /// Seconds without further changes to wait for before syncing.
+        #[arg(long, default_value_t = 10)]
+        debounce: u64,
+    },
+
+    /// Wait for the named targets' MTP devices or disks to be connected and
+    /// sync each as it shows up, returning once all of them are synced.
+    Wait {
+        /// Targets to wait for; every target on an MTP device or disk if none
+        /// are given.
+        targets: Vec<String>,
+    },
+
+    /// Check that the files synced to each target are still there and
+    /// intact, comparing their contents with the sources or transcodes.
+    Verify {
+        /// Targets to check; all of them if none are given.
+        targets: Vec<String>,
+
+        /// Push missing and damaged files again.
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Show what the recorded state says is on each target.
+    Status {
+        /// Targets to show; all of them if none are given.
+        targets: Vec<String>,
+    },
+
+    /// List past sync runs.
+    History {
+        /// Targets to show; all of them if none are given.
+        targets: Vec<String>,
+
+        /// How many of the most recent runs to show per target.
+        #[arg(short, long, default_value_t = 10)]
+        limit: usize,
+    },
+
+    /// Measure the loudness of each target's playlists, the way ReplayGain
+    /// profiles see it.
+    Loudness {
+        /// Targets to report on; all of them if none are given.
+        targets: Vec<String>,
+
+        /// List every song rather than just each playlist's summary.
+        #[arg(long)]
+        tracks: bool,
+    },
+
+    /// Inspect or trim the transcode caches.
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
 
-    /// Sync playlists to the phone over WebDAV (Evermusic).
-    #[arg(short, long)]
-    pub phone: bool,
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    /// Show how many transcodes each cache holds.
+    Stats,
 
-    /// Sync playlists to a disk destination.
-    #[arg(long)]
-    pub disk: bool,
+    /// Evict least recently used transcodes down to a size limit.
+    Prune {
+        /// The size to trim each cache to, e.g. `10G`; defaults to the
+        /// configured `cachesize`.
+        #[arg(long)]
+        max_size: Option<String>,
+    },
 
-    /// Transcode and sync playlists to an MTP watch.
-    #[arg(short, long)]
-    pub watch: bool,
+    /// Delete every cached transcode.
+    Clear,
 }
@@ -1,227 +1,288 @@
-//! The individual sync flows, one per destination, extracted out of `main`.
-
-use crate::config::Config;
-use crate::evermusic::Evermusic;
-use crate::rsync::Rsync;
-use crate::transcode::Transcoder;
-use crate::{m3u, watch};
-use anyhow::{Context, Result};
-use bluos_api_rs::{BluOS, Discovery};
-use glob::{MatchOptions, Pattern};
-use rayon::prelude::*;
-use std::collections::HashSet;
-use std::path::{Path, PathBuf};
-use swinsiandb::{Database, Playlist};
-
-/// Resolves a target's playlist selection into concrete playlists.
-///
-/// `names` are matched exactly (preserving the historical "first match wins"
-/// behaviour for duplicate names). `patterns` are globs matched against each
-/// playlist's folder path — e.g. `"Weatherall/*"` selects everything directly
-/// inside the "Weatherall" folder, `"Weatherall/**"` recurses. Results are
-/// de-duplicated by playlist id.
-fn resolve_playlists(db: &Database, names: &[String], patterns: &[String]) -> Result<Vec<Playlist>> {
-    let mut seen = HashSet::new();
-    let mut resolved = Vec::new();
-
-    for name in names {
-        let playlist = db
-            .get_playlist(name)
-            .with_context(|| format!("looking up playlist '{}'", name))?;
-        if seen.insert(playlist.playlist_id) {
-            resolved.push(playlist);
-        }
-    }
+//! Entry points for the CLI subcommands.
 
-    if !patterns.is_empty() {
-        let matchers = patterns
-            .iter()
-            .map(|p| Pattern::new(p).with_context(|| format!("invalid playlist pattern '{}'", p)))
-            .collect::<Result<Vec<_>>>()?;
-        // `*` stays within one folder level; `**` crosses folder boundaries.
-        let opts = MatchOptions {
-            require_literal_separator: true,
-            ..MatchOptions::new()
-        };
+use crate::cache::{self, Cache};
+use crate::cli::CacheAction;
+use crate::config::{Config, Target};
+use crate::error::Error;
+use crate::loudness::{self, Loudness, REFERENCE_LUFS};
+use crate::state::{format_timestamp, StateStore};
+use crate::targets::{self, format_bytes, Plan, SyncOptions};
+use crate::transcode::Backend;
+use anyhow::{bail, Context, Result};
+use swinsiandb::Database;
 
-        let mut pattern_hit = vec![false; matchers.len()];
-        for entry in db.get_playlists_with_paths()? {
-            let mut matched = false;
-            for (i, matcher) in matchers.iter().enumerate() {
-                if matcher.matches_with(&entry.path, opts) {
-                    pattern_hit[i] = true;
-                    matched = true;
-                }
-            }
-            if matched && seen.insert(entry.playlist.playlist_id) {
-                resolved.push(entry.playlist);
-            }
-        }
-
-        for (i, hit) in pattern_hit.iter().enumerate() {
-            if !hit {
-                warn!("pattern '{}' matched no playlists", patterns[i]);
-            }
+/// Syncs the named targets in the order given, or every configured target
+/// when `names` is empty. A target that fails doesn't stop the others; the
+/// failures, with the files they left behind, are summed up at the end.
+pub async fn sync(db: &Database, cfg: &Config, names: &[String], opts: SyncOptions) -> Result<()> {
+    let mut failures = Vec::new();
+    for target in select_targets(cfg, names)? {
+        info!("------------- {} -------------", target.name);
+        if let Err(e) = targets::sync(db, cfg, target, opts).await {
+            error!("{} failed: {:#}", target.name, e);
+            failures.push((&target.name, e));
         }
     }
 
-    Ok(resolved)
-}
-
-/// Returns the paths of every song in `playlist`, with the configured basepath
-/// prefix rewritten to `prefix`.
-fn playlist_files(
-    db: &Database,
-    playlist: &Playlist,
-    basepath: &str,
-    prefix: &str,
-) -> Result<Vec<String>> {
-    Ok(db
-        .get_playlist_songs(playlist)
-        .with_context(|| format!("reading songs for playlist '{}'", playlist.name))?
-        .into_iter()
-        .map(|t| t.path.replace(basepath, prefix))
-        .collect())
-}
+    if !opts.dry_run {
+        prune_caches(cfg)?;
+    }
 
-/// Collects the de-duplicated set of song paths across several `playlists`.
-fn unique_playlist_files(
-    db: &Database,
-    playlists: &[Playlist],
-    basepath: &str,
-    prefix: &str,
-) -> Result<Vec<String>> {
-    let mut files = HashSet::new();
-    for playlist in playlists {
-        info!("Collecting: {}", playlist.name);
-        files.extend(playlist_files(db, playlist, basepath, prefix)?);
+    if failures.is_empty() {
+        return Ok(());
     }
-    Ok(files.into_iter().collect())
+    eprintln!("Failed:");
+    for (name, e) in &failures {
+        eprintln!("  {}: {:#}", name, e);
+        let failed = e.chain().find_map(|e| e.downcast_ref::<Error>());
+        for file in failed.map_or(&[][..], Error::failed_files) {
+            eprintln!("    {}: {}", file.path, file.message);
+        }
+    }
+    bail!("{} of the targets failed", failures.len())
 }
 
-/// Syncs the configured playlists to the deck, then asks the BluOS amp to
-/// re-index its library.
-pub async fn sync_deck(db: &Database, cfg: &Config) -> Result<()> {
-    let playlists = resolve_playlists(db, &cfg.decksync.playlists, &cfg.decksync.patterns)?;
-    for playlist in &playlists {
-        info!("Syncing: {}", playlist.name);
-        let files = playlist_files(db, playlist, &cfg.basepath, "")?;
-
-        Rsync::new(&cfg.basepath, &cfg.decksync.destination)
-            .sync_selective(&files, false)
-            .await?;
-
-        let m3u_path = m3u::create_m3u(&playlist.name, &files).await?;
-        Rsync::new(&m3u_path, &cfg.decksync.destination)
-            .sync_file()
-            .await?;
+/// Trims every transcode cache to the configured `cachesize`, if any.
+pub fn prune_caches(cfg: &Config) -> Result<()> {
+    let Some(limit) = cfg.cache_limit() else {
+        return Ok(());
+    };
+    for dir in cfg.cache_dirs() {
+        let cache = Cache::new(dir);
+        let removed = cache
+            .prune(limit)
+            .with_context(|| format!("pruning {}", cache.dir().display()))?;
+        if removed.files > 0 {
+            info!(
+                "Evicted {} transcodes ({}) from {}",
+                removed.files,
+                format_bytes(removed.bytes),
+                cache.dir().display()
+            );
+        }
     }
-
-    info!("Re-indexing the BluOS library");
-    let device = Discovery::discover_one().await?;
-    info!("Found BluOS device on: {}", device.hostname);
-    BluOS::new_from_discovered(device)?.update_library().await?;
-
     Ok(())
 }
 
-/// Syncs the configured playlists to a disk destination, writing playlists into
-/// a separate playlist folder.
-pub async fn sync_disk(db: &Database, cfg: &Config) -> Result<()> {
-    let playlists = resolve_playlists(db, &cfg.disksync.playlists, &cfg.disksync.patterns)?;
-    for playlist in &playlists {
-        info!("Syncing: {}", playlist.name);
-        let files = playlist_files(db, playlist, &cfg.basepath, "../")?;
-
-        Rsync::new(&cfg.basepath, &cfg.disksync.destination)
-            .sync_selective(&files, false)
-            .await?;
-
-        let m3u_path = m3u::create_m3u(&playlist.name, &files).await?;
-        Rsync::new(&m3u_path, &cfg.disksync.playlistfolder)
-            .sync_file()
-            .await?;
+/// Checks the files synced to each of the named targets, or to every target
+/// when `names` is empty, listing those that are missing or differ. With
+/// `repair` they're pushed again; otherwise finding any is an error.
+pub async fn verify(db: &Database, cfg: &Config, names: &[String], repair: bool) -> Result<()> {
+    let mut failures = 0;
+    let mut damaged = 0;
+    for target in select_targets(cfg, names)? {
+        info!("------------- {} -------------", target.name);
+        let verification = match targets::verify(db, cfg, target, repair).await {
+            Ok(verification) => verification,
+            Err(e) => {
+                error!("{} failed: {:#}", target.name, e);
+                failures += 1;
+                continue;
+            }
+        };
+
+        println!(
+            "{}: {} files checked, {} missing, {} differ",
+            target.name,
+            verification.checked,
+            verification.missing.len(),
+            verification.mismatched.len()
+        );
+        if verification.unchecked > 0 {
+            println!(
+                "  {} songs changed since they were synced or have no cached transcode, \
+                 and weren't checked",
+                verification.unchecked
+            );
+        }
+        for path in &verification.missing {
+            println!("  {:<8} {}", "missing", path);
+        }
+        for path in &verification.mismatched {
+            println!("  {:<8} {}", "differs", path);
+        }
+        if verification.repaired > 0 {
+            println!("  pushed {} files again", verification.repaired);
+        }
+        if !repair && !verification.is_intact() {
+            damaged += verification.missing.len() + verification.mismatched.len();
+        }
     }
 
+    if failures > 0 {
+        bail!("{} of the targets couldn't be verified", failures);
+    }
+    if damaged > 0 {
+        bail!("{} files are missing or differ; pass --repair to push them again", damaged);
+    }
     Ok(())
 }
 
-/// Discovers the phone over mDNS, mounts its Evermusic WebDAV share and syncs
-/// the configured playlists to it.
-pub async fn sync_phone(db: &Database, cfg: &Config) -> Result<()> {
-    info!("Discovering Evermusic");
-    let evermusic = Evermusic::new(&cfg.evermusic.servicename, &cfg.evermusic.mountpath, None).await?;
-    info!(
-        "Found Evermusic WebDAV at {}:{}",
-        evermusic.phone.hostname, evermusic.phone.port
-    );
+/// Prints, per playlist of each target, how loud its songs are overall and
+/// how far apart they lie, measuring any songs not measured before.
+pub fn loudness(db: &Database, cfg: &Config, names: &[String], tracks: bool) -> Result<()> {
+    let store = StateStore::new(cfg.state_dir());
+    let mut cache = store
+        .load_loudness()
+        .context("loading loudness measurements")?;
+    let encoder = Backend::Auto.encoder();
 
-    let playlists = resolve_playlists(db, &cfg.evermusic.playlists, &cfg.evermusic.patterns)?;
-    let files = unique_playlist_files(db, &playlists, &cfg.basepath, "")?;
+    for target in select_targets(cfg, names)? {
+        let plan = Plan::for_target(db, target)?;
+        let files: Vec<&str> = plan.unique_files().into_iter().collect();
+        cache.analyse(encoder.as_ref(), &files);
+        store
+            .save_loudness(&cache)
+            .context("saving loudness measurements")?;
 
-    Rsync::new(&cfg.basepath, &format!("{}/", cfg.evermusic.mountpath))
-        .sync_selective(&files, false)
-        .await?;
+        println!("{}:", target.name);
+        for planned in &plan.playlists {
+            let measured: Vec<(&str, Loudness, f64)> = planned
+                .tracks
+                .iter()
+                .filter_map(|t| {
+                    let length = t.length.unwrap_or(1.0).max(1.0);
+                    Some((t.path.as_str(), cache.get(&t.path)?, length))
+                })
+                .collect();
+            if measured.is_empty() {
+                println!("  {}: nothing measured", planned.folder_path);
+                continue;
+            }
 
+            let levels = measured.iter().map(|(_, l, _)| l.integrated);
+            let quietest = levels.clone().fold(f64::INFINITY, f64::min);
+            let loudest = levels.fold(f64::NEG_INFINITY, f64::max);
+            let songs: Vec<(Loudness, f64)> = measured.iter().map(|(_, l, s)| (*l, *s)).collect();
+            let overall = loudness::combine(&songs);
+            println!(
+                "  {}: {} songs, {:.1} LUFS ({:+.1} dB to {} LUFS), {:.1} to {:.1} LUFS, \
+                 peak {:+.1} dBTP",
+                planned.folder_path,
+                measured.len(),
+                overall.integrated,
+                overall.gain(),
+                REFERENCE_LUFS,
+                quietest,
+                loudest,
+                overall.peak
+            );
+            if planned.tracks.len() > measured.len() {
+                println!("    {} songs not measured", planned.tracks.len() - measured.len());
+            }
+            if tracks {
+                for (path, l, _) in &measured {
+                    println!("    {:>6.1} LUFS {:>+5.1} dBTP  {}", l.integrated, l.peak, path);
+                }
+            }
+        }
+    }
     Ok(())
 }
 
-/// Transcodes the configured playlists to AAC and uploads any files missing
-/// from the MTP watch.
-pub async fn sync_watch(db: &Database, cfg: &Config) -> Result<()> {
-    info!("WATCH TIME: finding watch");
-    let mut watch = watch::Watch::new(cfg.watch.clone()).await?;
-    let transcoder = Transcoder::new("/tmp");
-    let basepath = Path::new(&cfg.basepath);
-
-    // Full source paths of every song we want on the watch.
-    let playlists = resolve_playlists(db, &cfg.watch.playlists, &cfg.watch.patterns)?;
-    let mut to_sync: HashSet<String> = HashSet::new();
-    for playlist in &playlists {
-        info!("Preparing '{}' for syncing", playlist.name);
-        to_sync.extend(db.get_playlist_songs(playlist)?.into_iter().map(|t| t.path));
+/// Runs a `cache` subcommand against every transcode cache folder.
+pub fn cache(cfg: &Config, action: &CacheAction) -> Result<()> {
+    let limit = match action {
+        CacheAction::Prune { max_size: Some(size) } => {
+            cache::parse_size(size).with_context(|| format!("invalid size '{}'", size))?
+        }
+        CacheAction::Prune { max_size: None } => cfg
+            .cache_limit()
+            .context("no --max-size given and no cachesize configured")?,
+        _ => 0,
+    };
+
+    for dir in cfg.cache_dirs() {
+        let cache = Cache::new(dir);
+        let (verb, stats) = match action {
+            CacheAction::Stats => ("holds", cache.stats()),
+            CacheAction::Prune { .. } => ("evicted", cache.prune(limit)),
+            CacheAction::Clear => ("cleared", cache.clear()),
+        };
+        let stats = stats.with_context(|| format!("reading {}", cache.dir().display()))?;
+        println!(
+            "{}: {} {} transcodes, {}",
+            cache.dir().display(),
+            verb,
+            stats.files,
+            format_bytes(stats.bytes)
+        );
     }
+    Ok(())
+}
 
-    // Only keep the files that aren't already on the device.
-    let to_transcode: HashSet<PathBuf> = to_sync
-        .iter()
-        .filter_map(|f| {
-            let src = Path::new(f);
-            let mut relative = pathdiff::diff_paths(src, basepath)?;
-            relative.set_extension("mp4");
-            (!watch.exists(&relative)).then(|| src.to_path_buf())
-        })
-        .collect();
-
-    info!("Transcoding {} files", to_transcode.len());
-    let transfers: Vec<watch::TransferObject> = to_transcode
-        .into_par_iter()
-        .map(|src| transcode_for_watch(&transcoder, basepath, src))
-        .collect::<Result<_>>()?;
-
-    info!("Uploading {} files", transfers.len());
-    for transfer in transfers {
-        info!("Syncing file: {:?}", transfer);
-        watch.put_file(transfer)?;
+/// Prints, per target, how many files the recorded state says are on it and
+/// when it was last synced.
+pub fn status(cfg: &Config, names: &[String]) -> Result<()> {
+    let store = StateStore::new(cfg.state_dir());
+    for target in select_targets(cfg, names)? {
+        let state = store
+            .load(&target.name)
+            .with_context(|| format!("loading state for '{}'", target.name))?;
+        let bytes = state.files.values().map(|f| f.size).sum();
+        println!(
+            "{}: {} files, {}",
+            target.name,
+            state.files.len(),
+            format_bytes(bytes)
+        );
+        match state.history.last() {
+            Some(run) => println!(
+                "  last sync {}: {} pushed, {} skipped, {} deleted",
+                format_timestamp(run.finished_at),
+                run.pushed,
+                run.skipped,
+                run.deleted
+            ),
+            None => println!("  never synced"),
+        }
     }
+    Ok(())
+}
 
+/// Prints the `limit` most recent runs of each target, newest first.
+pub fn history(cfg: &Config, names: &[String], limit: usize) -> Result<()> {
+    let store = StateStore::new(cfg.state_dir());
+    for target in select_targets(cfg, names)? {
+        let state = store
+            .load(&target.name)
+            .with_context(|| format!("loading state for '{}'", target.name))?;
+        println!("{}:", target.name);
+        for run in state.history.iter().rev().take(limit) {
+            println!(
+                "  {}  {:>4}s  {} pushed ({}), {} skipped, {} deleted",
+                format_timestamp(run.started_at),
+                run.finished_at.saturating_sub(run.started_at),
+                run.pushed,
+                format_bytes(run.bytes),
+                run.skipped,
+                run.deleted
+            );
+        }
+    }
     Ok(())
 }
 
-/// Transcodes a single source file and builds the corresponding watch transfer.
-fn transcode_for_watch(
-    transcoder: &Transcoder,
-    basepath: &Path,
-    src: PathBuf,
-) -> Result<watch::TransferObject> {
-    let transcoded = transcoder.transcode(&src)?;
-
-    let mut destination = pathdiff::diff_paths(&src, basepath)
-        .with_context(|| format!("computing destination path for {:?}", src))?;
-    destination.set_extension("mp4");
-
-    Ok(watch::TransferObject {
-        transcoded,
-        destination,
-    })
+/// Looks up each of `names` in the config, failing on the first unknown name.
+/// An empty list selects every target.
+pub fn select_targets<'a>(cfg: &'a Config, names: &[String]) -> Result<Vec<&'a Target>> {
+    if names.is_empty() {
+        return Ok(cfg.targets.iter().collect());
+    }
+
+    names
+        .iter()
+        .map(|name| match cfg.target(name) {
+            Some(target) => Ok(target),
+            None => bail!(
+                "unknown target '{}' (configured: {})",
+                name,
+                cfg.targets
+                    .iter()
+                    .map(|t| t.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        })
+        .collect()
 }
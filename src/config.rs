@@ -1,26 +1,139 @@
+use crate::cache;
+use crate::copier::{Backend, Compare};
+use crate::destination::Destination;
 use crate::error::Error;
+use crate::filesystem::{Filesystem, Normalisation};
+use crate::playlist::{default_formats, PlaylistFormat};
+use crate::transcode::Profile;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 impl Config {
     pub fn load_config(path: &Path) -> Result<Arc<Config>, Error> {
         let data = fs::read_to_string(path)?;
         let cfg: Config = toml::from_str(&data)?;
+
+        if let Some(size) = &cfg.cachesize {
+            if cache::parse_size(size).is_none() {
+                return Err(Error::InvalidSize(size.clone()));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for target in &cfg.targets {
+            if !names.insert(target.name.as_str()) {
+                return Err(Error::DuplicateTarget(target.name.clone()));
+            }
+            if let Some(profile) = &target.profile {
+                if cfg.profile(profile).is_none() {
+                    let (target, profile) = (target.name.clone(), profile.clone());
+                    return Err(Error::UnknownProfile { target, profile });
+                }
+            }
+
+            let native = match &target.kind {
+                TargetKind::Rsync(c) if c.copier == Backend::Native => vec![&c.destination],
+                TargetKind::Disk(c) if c.copier == Backend::Native => {
+                    vec![&c.destination, &c.playlistfolder]
+                }
+                _ => Vec::new(),
+            };
+            let remote = native
+                .into_iter()
+                .find(|d| matches!(Destination::parse(d), Destination::Remote { .. }));
+            if let Some(destination) = remote {
+                return Err(Error::NativeRemote {
+                    target: target.name.clone(),
+                    destination: destination.clone(),
+                });
+            }
+        }
+
         Ok(Arc::new(cfg))
     }
+
+    /// Where per-target sync state is kept: `statedir` if set, otherwise
+    /// `$XDG_STATE_HOME/shittysync` or `~/.local/state/shittysync`.
+    pub fn state_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.statedir {
+            return PathBuf::from(dir);
+        }
+        let base = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/state")))
+            .unwrap_or_else(std::env::temp_dir);
+        base.join("shittysync")
+    }
+
+    /// Where transcodes are cached: `cachedir` if set, otherwise
+    /// `$XDG_CACHE_HOME/shittysync` or `~/.cache/shittysync`. MTP targets use
+    /// their `workspace` instead.
+    pub fn cache_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.cachedir {
+            return PathBuf::from(dir);
+        }
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        base.join("shittysync")
+    }
+
+    /// Every folder transcodes may be cached in: the shared cache and each MTP
+    /// target's workspace.
+    pub fn cache_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![self.cache_dir()];
+        for target in &self.targets {
+            if let TargetKind::Mtp(mtp) = &target.kind {
+                let dir = PathBuf::from(&mtp.workspace);
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+        dirs
+    }
+
+    /// The configured `cachesize` limit in bytes, if any.
+    pub fn cache_limit(&self) -> Option<u64> {
+        self.cachesize.as_deref().and_then(cache::parse_size)
+    }
+
+    /// Looks up a transcoding profile by name: those declared in the config
+    /// first, then the built-in ones.
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        self.profiles
+            .get(name)
+            .cloned()
+            .or_else(|| Profile::builtin(name))
+    }
+
+    /// Looks up a configured target by name.
+    pub fn target(&self, name: &str) -> Option<&Target> {
+        self.targets.iter().find(|t| t.name == name)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub basepath: String,
+    #[serde(default)]
+    pub statedir: Option<String>,
+    #[serde(default)]
+    pub cachedir: Option<String>,
+    /// Upper bound on each transcode cache, e.g. `"20G"`; least recently used
+    /// transcodes are evicted after every sync.
+    #[serde(default)]
+    pub cachesize: Option<String>,
     pub swinsian: SwinsianConfig,
-    pub decksync: DeckSync,
-    pub disksync: DiskSync,
-    pub evermusic: Evermusic,
-    pub watch: Watch,
+    #[serde(default, rename = "profile")]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default, rename = "target")]
+    pub targets: Vec<Target>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -29,52 +142,214 @@ pub struct SwinsianConfig {
     pub dbpath: String,
 }
 
-/// Selects which playlists a sync target operates on.
+/// A named sync destination, declared as a `[[target]]` table.
 ///
 /// `playlists` lists exact playlist names (current behaviour: the first match
 /// wins if names collide). `patterns` lists glob patterns matched against each
 /// playlist's folder path, e.g. `"Weatherall/*"` selects every playlist
 /// directly inside the "Weatherall" folder (use `**` to recurse). The two are
 /// combined and de-duplicated.
+///
+/// `profile` names the transcoding profile songs are converted with before
+/// they're pushed; without one they're copied as they are (MTP targets
+/// default to `aac-256k`).
+///
+/// `compare` sets how strictly songs are checked for changes, see
+/// [`Compare`]: a song recorded as pushed is pushed again once it differs
+/// from what was recorded, and a file already on the destination is
+/// replaced once it differs from the song.
+///
+/// The remaining keys depend on `kind`, see [`TargetKind`].
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct DeckSync {
-    pub destination: String,
+pub struct Target {
+    pub name: String,
     #[serde(default)]
     pub playlists: Vec<String>,
     #[serde(default)]
     pub patterns: Vec<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub compare: Compare,
+    #[serde(flatten)]
+    pub kind: TargetKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TargetKind {
+    /// rsync to a single destination, with playlists written alongside the
+    /// music.
+    Rsync(RsyncConfig),
+    /// rsync to a destination with playlists in a separate folder.
+    Disk(DiskConfig),
+    /// The Evermusic WebDAV share on a phone, discovered over mDNS.
+    Webdav(WebdavConfig),
+    /// An MTP device such as a watch; files are transcoded before upload.
+    Mtp(MtpConfig),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct DiskSync {
+pub struct RsyncConfig {
+    pub destination: String,
+    /// Ask a BluOS player on the network to re-index its library afterwards.
+    #[serde(default)]
+    pub bluos: bool,
+    /// Playlist formats to write, see [`PlaylistFormat`].
+    #[serde(default = "default_formats")]
+    pub formats: Vec<PlaylistFormat>,
+    /// Delete audio files and playlists on the destination that no selected
+    /// playlist references any more.
+    #[serde(default)]
+    pub mirror: bool,
+    /// Write each album's cover into its folder under this name, e.g.
+    /// `folder.jpg`.
+    #[serde(default)]
+    pub coverfile: Option<String>,
+    /// The filesystem the destination is formatted with. On `fat32` and
+    /// `exfat`, names are rewritten into ones it accepts.
+    #[serde(default)]
+    pub filesystem: Filesystem,
+    /// The Unicode normalisation form for names on the destination and in
+    /// its playlists, e.g. `nfc` for a Linux NAS fed from macOS.
+    #[serde(default)]
+    pub normalisation: Normalisation,
+    /// What copies the files: `rsync` (the default) or `native`, which only
+    /// works when the destination is a local path or mount.
+    #[serde(default)]
+    pub copier: Backend,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskConfig {
     pub destination: String,
     pub playlistfolder: String,
+    /// Playlist formats to write, see [`PlaylistFormat`].
+    #[serde(default = "default_formats")]
+    pub formats: Vec<PlaylistFormat>,
+    /// Like [`RsyncConfig::mirror`]; stale playlists are removed from
+    /// `playlistfolder`.
     #[serde(default)]
-    pub playlists: Vec<String>,
+    pub mirror: bool,
+    /// Like [`RsyncConfig::coverfile`].
     #[serde(default)]
-    pub patterns: Vec<String>,
+    pub coverfile: Option<String>,
+    /// Like [`RsyncConfig::filesystem`].
+    #[serde(default)]
+    pub filesystem: Filesystem,
+    /// Like [`RsyncConfig::normalisation`].
+    #[serde(default)]
+    pub normalisation: Normalisation,
+    /// Like [`RsyncConfig::copier`].
+    #[serde(default)]
+    pub copier: Backend,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Evermusic {
+pub struct WebdavConfig {
     pub servicename: String,
     pub mountpath: String,
+    /// Playlist formats to write next to the music, see [`PlaylistFormat`].
+    #[serde(default = "default_formats")]
+    pub formats: Vec<PlaylistFormat>,
+    /// Delete audio files and playlists on the phone that no selected
+    /// playlist references any more.
     #[serde(default)]
-    pub playlists: Vec<String>,
+    pub mirror: bool,
+    /// Like [`RsyncConfig::filesystem`]; the share usually takes any name.
     #[serde(default)]
-    pub patterns: Vec<String>,
+    pub filesystem: Filesystem,
+    /// Like [`RsyncConfig::normalisation`].
+    #[serde(default)]
+    pub normalisation: Normalisation,
+    /// Like [`RsyncConfig::copier`]; `native` copies onto the mounted share.
+    #[serde(default)]
+    pub copier: Backend,
 }
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Watch {
+pub struct MtpConfig {
+    /// Where this target's transcodes are cached.
     pub workspace: String,
     pub device_name: String,
-    pub base_folder: String,
+    /// The device's serial number, to tell apart devices that share a name.
+    /// When set, the device is found by it rather than by `device_name`.
     #[serde(default)]
-    pub playlists: Vec<String>,
-    #[serde(default)]
-    pub patterns: Vec<String>,
+    pub serial: Option<String>,
+    pub base_folder: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_target_array() {
+        let cfg: Config = toml::from_str(
+            r#"
+            basepath = "/music"
+
+            [swinsian]
+            dbpath = "/db.sqlite"
+
+            [[target]]
+            name = "nas-living"
+            kind = "rsync"
+            destination = "nas:/media/Solo/"
+            bluos = true
+            playlists = ["A"]
+
+            [[target]]
+            name = "watch"
+            kind = "mtp"
+            workspace = "/tmp/watch"
+            deviceName = "My Watch"
+            baseFolder = "Music"
+            patterns = ["Running/*"]
+            compare = "mtime"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.targets.len(), 2);
+        let nas = cfg.target("nas-living").unwrap();
+        assert_eq!(nas.playlists, vec!["A"]);
+        assert!(matches!(&nas.kind, TargetKind::Rsync(r) if r.bluos));
+
+        let watch = cfg.target("watch").unwrap();
+        assert_eq!(watch.patterns, vec!["Running/*"]);
+        assert!(matches!(&watch.kind, TargetKind::Mtp(m) if m.device_name == "My Watch"));
+        assert_eq!((nas.compare, watch.compare), (Compare::Existing, Compare::Mtime));
+    }
+    #[test]
+    fn webdav_takes_formats_and_filesystem() {
+        let cfg: Config = toml::from_str(
+            r#"
+            basepath = "/music"
+
+            [swinsian]
+            dbpath = "/db.sqlite"
+
+            [[target]]
+            name = "phone"
+            kind = "webdav"
+            servicename = "evermusic.webdav"
+            mountpath = "/tmp/phone"
+            formats = ["m3u8", "xspf"]
+            filesystem = "exfat"
+            "#,
+        )
+        .unwrap();
+
+        let TargetKind::Webdav(webdav) = &cfg.target("phone").unwrap().kind else {
+            panic!("not a webdav target");
+        };
+        assert_eq!(webdav.formats, vec![PlaylistFormat::M3u8, PlaylistFormat::Xspf]);
+        assert_eq!(webdav.filesystem, Filesystem::Exfat);
+    }
 }
@@ -0,0 +1,174 @@
+//! Moving files onto rsync, disk and WebDAV destinations: with the `rsync`
+//! executable, or natively for destinations that are local paths or mounts.
+
+mod native;
+
+use crate::error::Error;
+use crate::rsync::Rsync;
+use crate::targets::format_bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+pub use native::Native;
+
+/// Copies files from a source folder onto a destination.
+///
+/// `files` are given relative to `source`, with or without a leading slash,
+/// and keep that relative path below `dest`.
+pub trait Copier {
+    /// How a file already on the destination is told apart from its source.
+    fn compare(&self) -> Compare;
+
+    /// Which of `files` [`copy`](Copier::copy) would copy, relative to
+    /// `source` without a leading slash. Nothing is copied.
+    async fn missing(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+    ) -> Result<HashSet<String>, Error>;
+
+    /// Copies those of `files` that aren't on `dest` yet, or differ from
+    /// what's there, calling `copied` with each file as it lands.
+    async fn copy(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+        copied: impl FnMut(&str),
+    ) -> Result<SyncStats, Error>;
+
+    /// Copies everything below `source` into `dest`, replacing whatever is
+    /// there under the same names.
+    async fn copy_tree(&self, source: &str, dest: &str) -> Result<(), Error>;
+}
+
+/// Tally of what a copy did.
+#[derive(Debug, Default, Clone)]
+pub struct SyncStats {
+    /// Files that weren't on the destination before.
+    pub created: usize,
+    /// Files that replaced an older version on the destination.
+    pub updated: usize,
+    pub deleted: usize,
+    /// Files that were already up to date.
+    pub skipped: usize,
+    /// Bytes sent, as far as the copier can tell.
+    pub bytes: u64,
+    pub errors: Vec<FileError>,
+}
+
+impl SyncStats {
+    pub fn copied(&self) -> usize {
+        self.created + self.updated
+    }
+}
+
+impl fmt::Display for SyncStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} created, {} updated, {} skipped",
+            self.created, self.updated, self.skipped
+        )?;
+        if self.deleted > 0 {
+            write!(f, ", {} deleted", self.deleted)?;
+        }
+        if self.bytes > 0 {
+            write!(f, ", {} sent", format_bytes(self.bytes))?;
+        }
+        if !self.errors.is_empty() {
+            write!(f, ", {} failed", self.errors.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// A file a copy failed on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileError {
+    pub path: String,
+    pub message: String,
+}
+
+/// Which program does a target's copying.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Rsync,
+    /// In-process, for destinations that are local paths or mounts.
+    Native,
+}
+
+impl Backend {
+    pub fn copier(self, compare: Compare) -> AnyCopier {
+        match self {
+            Backend::Rsync => AnyCopier::Rsync(Rsync::new(compare)),
+            Backend::Native => AnyCopier::Native(Native::new(compare)),
+        }
+    }
+}
+
+/// How a file already on the destination is told apart from its source, and
+/// a song from the way it was when it was last pushed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compare {
+    /// Any file by that name counts as up to date.
+    #[default]
+    Existing,
+    /// Files whose size or modification time differ are copied again.
+    Mtime,
+    /// Files whose size or contents differ are copied again. Every song is
+    /// read in full on each sync.
+    Checksum,
+}
+
+/// The copier a target's config picks.
+pub enum AnyCopier {
+    Rsync(Rsync),
+    Native(Native),
+}
+
+impl Copier for AnyCopier {
+    fn compare(&self) -> Compare {
+        match self {
+            AnyCopier::Rsync(rsync) => rsync.compare(),
+            AnyCopier::Native(native) => native.compare(),
+        }
+    }
+
+    async fn missing(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+    ) -> Result<HashSet<String>, Error> {
+        match self {
+            AnyCopier::Rsync(rsync) => rsync.missing(source, dest, files).await,
+            AnyCopier::Native(native) => native.missing(source, dest, files).await,
+        }
+    }
+
+    async fn copy(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+        copied: impl FnMut(&str),
+    ) -> Result<SyncStats, Error> {
+        match self {
+            AnyCopier::Rsync(rsync) => rsync.copy(source, dest, files, copied).await,
+            AnyCopier::Native(native) => native.copy(source, dest, files, copied).await,
+        }
+    }
+
+    async fn copy_tree(&self, source: &str, dest: &str) -> Result<(), Error> {
+        match self {
+            AnyCopier::Rsync(rsync) => rsync.copy_tree(source, dest).await,
+            AnyCopier::Native(native) => native.copy_tree(source, dest).await,
+        }
+    }
+}
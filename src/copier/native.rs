@@ -0,0 +1,261 @@
+use super::{Compare, Copier, FileError, SyncStats};
+use crate::error::Error;
+use crate::state::hash_file;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+/// Modification times this close together count as the same; FAT only keeps
+/// them to two seconds.
+const MODIFY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Tells apart the temporary files of copies running at the same time.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Copies in-process, several files at a time. Each file is written under a
+/// temporary name next to its destination and renamed into place, so an
+/// interrupted sync never leaves half a song behind.
+#[derive(Debug, Clone, Copy)]
+pub struct Native {
+    compare: Compare,
+}
+
+impl Native {
+    pub fn new(compare: Compare) -> Native {
+        Native { compare }
+    }
+
+    /// Whether `source` has to be copied over `dest`.
+    fn differs(&self, source: &Path, dest: &Path) -> io::Result<bool> {
+        let existing = match fs::metadata(dest) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        let meta = fs::metadata(source)?;
+        Ok(match self.compare {
+            Compare::Existing => false,
+            Compare::Mtime => {
+                meta.len() != existing.len()
+                    || !same_time(meta.modified()?, existing.modified()?)
+            }
+            Compare::Checksum => {
+                meta.len() != existing.len() || hash_file(source)? != hash_file(dest)?
+            }
+        })
+    }
+}
+
+impl Copier for Native {
+    fn compare(&self) -> Compare {
+        self.compare
+    }
+
+    async fn missing(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+    ) -> Result<HashSet<String>, Error> {
+        let native = *self;
+        let jobs = jobs(source, dest, files);
+        blocking(move || {
+            let mut missing = HashSet::new();
+            let differs: Vec<io::Result<bool>> =
+                jobs.par_iter().map(|(_, from, to)| native.differs(from, to)).collect();
+            for ((relative, _, _), differs) in jobs.into_iter().zip(differs) {
+                if differs? {
+                    missing.insert(relative);
+                }
+            }
+            Ok(missing)
+        })
+        .await
+    }
+
+    async fn copy(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+        mut copied: impl FnMut(&str),
+    ) -> Result<SyncStats, Error> {
+        let native = *self;
+        let jobs = jobs(source, dest, files);
+        let dest = dest.to_string();
+        // The copies run on the blocking pool; each one that lands is
+        // reported back here as it happens.
+        let (landed, mut landing) = mpsc::unbounded_channel();
+        let work = tokio::task::spawn_blocking(move || {
+            let results: Vec<io::Result<Option<(bool, u64)>>> = jobs
+                .par_iter()
+                .map(|(relative, from, to)| {
+                    if !native.differs(from, to)? {
+                        return Ok(None);
+                    }
+                    let existed = to.exists();
+                    let bytes = copy_file(from, to)?;
+                    let _ = landed.send(relative.clone());
+                    Ok(Some((existed, bytes)))
+                })
+                .collect();
+
+            let mut stats = SyncStats::default();
+            let (mut full, mut denied) = (false, false);
+            for ((relative, _, _), result) in jobs.into_iter().zip(results) {
+                match result {
+                    Ok(None) => stats.skipped += 1,
+                    Ok(Some((existed, bytes))) => {
+                        if existed {
+                            stats.updated += 1;
+                        } else {
+                            stats.created += 1;
+                        }
+                        stats.bytes += bytes;
+                    }
+                    Err(e) => {
+                        warn!("could not copy {}: {}", relative, e);
+                        full |= e.kind() == io::ErrorKind::StorageFull;
+                        denied |= e.kind() == io::ErrorKind::PermissionDenied;
+                        let message = e.to_string();
+                        stats.errors.push(FileError { path: relative, message });
+                    }
+                }
+            }
+
+            if stats.errors.is_empty() {
+                return Ok(stats);
+            }
+            let failed = stats.errors;
+            Err(if full {
+                Error::DiskFull { dest, failed }
+            } else if denied {
+                let message = failed[0].message.clone();
+                Error::PermissionDenied {
+                    dest,
+                    message,
+                    failed,
+                }
+            } else {
+                Error::PartialTransfer { dest, failed }
+            })
+        });
+        while let Some(relative) = landing.recv().await {
+            copied(&relative);
+        }
+        let stats = work.await.map_err(io::Error::other)??;
+
+        info!("{}", stats);
+        Ok(stats)
+    }
+
+    async fn copy_tree(&self, source: &str, dest: &str) -> Result<(), Error> {
+        let (source, dest) = (PathBuf::from(source), PathBuf::from(dest));
+        blocking(move || {
+            let mut files = Vec::new();
+            walk(&source, &mut files)?;
+            for file in files {
+                let relative = file.strip_prefix(&source).unwrap_or(&file);
+                copy_file(&file, &dest.join(relative))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Each of `files` with the paths it's copied from and to.
+fn jobs(source: &str, dest: &str, files: &[String]) -> Vec<(String, PathBuf, PathBuf)> {
+    files
+        .iter()
+        .map(|file| {
+            let relative = file.trim_start_matches('/');
+            let from = Path::new(source).join(relative);
+            let to = Path::new(dest).join(relative);
+            (relative.to_string(), from, to)
+        })
+        .collect()
+}
+
+/// Runs blocking file work off the async runtime.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Copies `source`, following symlinks, to `dest` by way of a temporary file
+/// in the same folder, and gives it the source's modification time. Returns
+/// the number of bytes copied.
+fn copy_file(source: &Path, dest: &Path) -> io::Result<u64> {
+    let folder = dest.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(folder)?;
+    let temp = folder.join(format!(
+        ".shittysync-{}-{}.part",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = fs::copy(source, &temp).and_then(|bytes| {
+        let modified = fs::metadata(source)?.modified()?;
+        File::options().write(true).open(&temp)?.set_modified(modified)?;
+        fs::rename(&temp, dest)?;
+        Ok(bytes)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn same_time(a: SystemTime, b: SystemTime) -> bool {
+    let apart = a.duration_since(b).unwrap_or_else(|e| e.duration());
+    apart <= MODIFY_WINDOW
+}
+
+/// Collects the files below `dir`, following symlinks.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_and_copies_in_place() {
+        let dir = std::env::temp_dir().join(format!("shittysync-copy-{}", std::process::id()));
+        let (source, dest) = (dir.join("song.flac"), dir.join("out/A/song.flac"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&source, "new audio").unwrap();
+
+        let existing = Native::new(Compare::Existing);
+        assert!(existing.differs(&source, &dest).unwrap());
+        copy_file(&source, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"new audio");
+        assert_eq!(fs::read_dir(dest.parent().unwrap()).unwrap().count(), 1);
+
+        let mtime = Native::new(Compare::Mtime);
+        let checksum = Native::new(Compare::Checksum);
+        assert!(!mtime.differs(&source, &dest).unwrap());
+        fs::write(&dest, "old audio").unwrap();
+        assert!(!existing.differs(&source, &dest).unwrap());
+        assert!(checksum.differs(&source, &dest).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
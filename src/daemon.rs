@@ -0,0 +1,463 @@
+//! Syncing in the background: the Swinsian library is watched for changes
+//! and, once edits settle down, the targets whose playlists changed are
+//! synced. Targets on MTP devices and disks are also synced whenever their
+//! device is connected.
+//!
+//! What happens is appended to a log file, and each target's latest state
+//! kept in a status file, both in the state directory, so a daemon started
+//! from launchd or cron can be checked on.
+
+use crate::commands;
+use crate::config::{Config, Target, TargetKind};
+use crate::destination::Destination;
+use crate::state::{self, Activity, DaemonStatus, StateStore, TargetStatus};
+use crate::targets::{self, Plan, SyncOptions};
+use crate::watch;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use swinsiandb::Database;
+
+/// How often the watched files and devices are looked at.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where removable disks get mounted, on macOS and Linux.
+const MOUNT_ROOTS: &[&str] = &["/Volumes", "/media", "/run/media", "/mnt"];
+
+/// Writes the log and status file.
+struct Journal {
+    store: StateStore,
+    status: DaemonStatus,
+}
+
+impl Journal {
+    fn new(cfg: &Config) -> Journal {
+        let store = StateStore::new(cfg.state_dir());
+        info!(
+            "Logging to {}, status in {}",
+            store.log_path().display(),
+            store.status_path().display()
+        );
+        Journal {
+            store,
+            status: DaemonStatus {
+                pid: std::process::id(),
+                ..DaemonStatus::default()
+            },
+        }
+    }
+
+    /// Appends `message` to the log.
+    fn log(&self, message: &str) {
+        let line = format!("{}  {}", state::format_timestamp(state::now()), message);
+        if let Err(e) = self.store.append_log(&line) {
+            warn!("could not write to {}: {}", self.store.log_path().display(), e);
+        }
+    }
+
+    /// Records that `target` entered `activity`, logging it and updating the
+    /// status file.
+    fn note(&mut self, target: &str, activity: Activity, error: Option<String>) {
+        match &error {
+            Some(error) => self.log(&format!("{}: {:?}: {}", target, activity, error)),
+            None => self.log(&format!("{}: {:?}", target, activity)),
+        }
+
+        let now = state::now();
+        let status = self.status.targets.entry(target.to_string()).or_insert(TargetStatus {
+            activity,
+            since: now,
+            last_synced: None,
+            error: None,
+        });
+        status.activity = activity;
+        status.since = now;
+        match activity {
+            Activity::Synced => {
+                status.last_synced = Some(now);
+                status.error = None;
+            }
+            Activity::Failed => status.error = error,
+            _ => {}
+        }
+        self.status.updated_at = now;
+        if let Err(e) = self.store.save_status(&self.status) {
+            warn!("could not write {}: {}", self.store.status_path().display(), e);
+        }
+    }
+}
+
+/// Notices writes to a set of files by their size and modification time.
+/// Files that don't exist count as a state of their own.
+struct Poll {
+    paths: Vec<PathBuf>,
+    seen: Vec<Option<(u64, SystemTime)>>,
+}
+
+impl Poll {
+    fn new(paths: Vec<PathBuf>) -> Poll {
+        let seen = paths.iter().map(|p| stat(p)).collect();
+        Poll { paths, seen }
+    }
+
+    /// Whether any of the files changed since the last call.
+    fn changed(&mut self) -> bool {
+        let now: Vec<_> = self.paths.iter().map(|p| stat(p)).collect();
+        let changed = now != self.seen;
+        self.seen = now;
+        changed
+    }
+}
+
+fn stat(path: &Path) -> Option<(u64, SystemTime)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+/// Keeps track of whether the device each target on an MTP device or
+/// removable disk syncs to is connected.
+#[derive(Default)]
+struct Devices {
+    connected: HashMap<String, bool>,
+}
+
+impl Devices {
+    /// Looks at which of the `targets`' devices are connected, returning the
+    /// targets whose device showed up since the last look. Devices already
+    /// connected on the first look count as having shown up.
+    fn arrivals(&mut self, targets: &[&Target], journal: &mut Journal) -> Vec<String> {
+        let mtp = targets.iter().any(|t| matches!(t.kind, TargetKind::Mtp(_)));
+        let plugged_in = if mtp { watch::connected() } else { Vec::new() };
+
+        let mut arrived = Vec::new();
+        for target in targets {
+            let now = match &target.kind {
+                TargetKind::Mtp(cfg) => plugged_in
+                    .iter()
+                    .any(|(name, serial)| watch::is_device(cfg, name, serial)),
+                _ => match on_disk(target) {
+                    Some(destination) => mounted(destination),
+                    None => continue,
+                },
+            };
+            let before = self.connected.insert(target.name.clone(), now);
+            match (before, now) {
+                (Some(false) | None, true) => {
+                    journal.note(&target.name, Activity::Connected, None);
+                    arrived.push(target.name.clone());
+                }
+                (Some(true) | None, false) => journal.note(&target.name, Activity::Waiting, None),
+                _ => {}
+            }
+        }
+        arrived
+    }
+
+    /// Whether `target`'s device was disconnected at the last look.
+    fn absent(&self, target: &str) -> bool {
+        self.connected.get(target) == Some(&false)
+    }
+}
+
+/// Whether `target` syncs to a device that comes and goes: an MTP device, or
+/// a disk mounted below one of the `MOUNT_ROOTS`.
+fn removable(target: &Target) -> bool {
+    matches!(target.kind, TargetKind::Mtp(_)) || on_disk(target).is_some()
+}
+
+/// The folder `target` copies to, when it's a local path below one of the
+/// `MOUNT_ROOTS`.
+fn on_disk(target: &Target) -> Option<&Path> {
+    let destination = match &target.kind {
+        TargetKind::Rsync(cfg) => &cfg.destination,
+        TargetKind::Disk(cfg) => &cfg.destination,
+        _ => return None,
+    };
+    let Destination::Local(_) = Destination::parse(destination) else {
+        return None;
+    };
+    let path = Path::new(destination);
+    MOUNT_ROOTS.iter().any(|root| path.starts_with(root)).then_some(path)
+}
+
+/// Whether `path` is a folder on a disk mounted below one of the
+/// `MOUNT_ROOTS`: it or one of its parents below the root has another device
+/// id than its own parent. A mount point with nothing mounted is a folder on
+/// the host's own disk, which mustn't be synced to in the disk's place.
+fn mounted(path: &Path) -> bool {
+    if !path.is_dir() {
+        return false;
+    }
+    let device = |path: &Path| fs::metadata(path).ok().map(|m| m.dev());
+    path.ancestors()
+        .take_while(|folder| !MOUNT_ROOTS.iter().any(|root| *folder == Path::new(root)))
+        .any(|folder| {
+            let parent = folder.parent().and_then(device);
+            parent.is_some() && device(folder) != parent
+        })
+}
+
+/// The library's files: Swinsian writes through SQLite's write-ahead log
+/// before changes reach the database itself.
+fn library_files(cfg: &Config) -> Vec<PathBuf> {
+    let db = PathBuf::from(&cfg.swinsian.dbpath);
+    let wal = PathBuf::from(format!("{}-wal", cfg.swinsian.dbpath));
+    vec![db, wal]
+}
+
+fn open_database(cfg: &Config) -> Result<Database> {
+    Database::from_file(Path::new(&cfg.swinsian.dbpath)).context("opening Swinsian database")
+}
+
+/// Sleeps until the next look, returning false if interrupted instead.
+async fn tick() -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(POLL_INTERVAL) => true,
+        _ = tokio::signal::ctrl_c() => false,
+    }
+}
+
+/// Watches the library and the config file at `config`, starting from `cfg`,
+/// until interrupted. Once neither has changed for `debounce`, the targets
+/// whose playlists or config entry changed since they were last synced are
+/// synced, except those whose device isn't connected; those sync when it
+/// shows up. A config that fails to load is reported and the previous one
+/// kept.
+pub async fn run(config: &Path, mut cfg: Arc<Config>, debounce: Duration) -> Result<()> {
+    let mut journal = Journal::new(&cfg);
+    let mut library = Poll::new(library_files(&cfg));
+    let mut config_file = Poll::new(vec![config.to_path_buf()]);
+    let mut devices = Devices::default();
+    // When to sync next. Anything that changed while the daemon wasn't
+    // running is caught up on straight away.
+    let mut pending = Some(Instant::now());
+    let mut previous: Option<Arc<Config>> = None;
+
+    info!("Watching {} for changes", cfg.swinsian.dbpath);
+    journal.log(&format!("watching {}", cfg.swinsian.dbpath));
+    loop {
+        if config_file.changed() {
+            match Config::load_config(config) {
+                Ok(reloaded) => {
+                    info!("Reloaded {}", config.display());
+                    journal.log(&format!("reloaded {}", config.display()));
+                    previous.get_or_insert_with(|| cfg.clone());
+                    cfg = reloaded;
+                    library = Poll::new(library_files(&cfg));
+                }
+                Err(e) => {
+                    error!("not reloading {}: {:#}", config.display(), e);
+                    journal.log(&format!("not reloading {}: {:#}", config.display(), e));
+                }
+            }
+            pending = Some(Instant::now() + debounce);
+        }
+        if library.changed() {
+            pending = Some(Instant::now() + debounce);
+        }
+
+        let watched: Vec<&Target> = cfg.targets.iter().filter(|t| removable(t)).collect();
+        let arrived = devices.arrivals(&watched, &mut journal);
+        if !arrived.is_empty() {
+            if let Err(e) = sync_each(&cfg, &arrived, &mut journal).await {
+                error!("{:#}", e);
+            }
+        }
+
+        if pending.is_some_and(|at| Instant::now() >= at) {
+            pending = None;
+            let previous = previous.take();
+            if let Err(e) = sync_changed(&cfg, previous.as_deref(), &devices, &mut journal).await {
+                error!("{:#}", e);
+            }
+        }
+
+        if !tick().await {
+            info!("Stopping");
+            journal.log("stopped");
+            return Ok(());
+        }
+    }
+}
+
+/// Waits for the devices of the named targets, or of every target on an MTP
+/// device or disk when `names` is empty, and syncs each target once its
+/// device is connected. Returns once all of them have been synced.
+pub async fn wait(cfg: &Config, names: &[String]) -> Result<()> {
+    let mut waiting: Vec<&Target> = commands::select_targets(cfg, names)?;
+    if names.is_empty() {
+        waiting.retain(|t| removable(t));
+    }
+    if let Some(target) = waiting.iter().find(|t| !removable(t)) {
+        bail!("'{}' doesn't sync to an MTP device or disk", target.name);
+    }
+    if waiting.is_empty() {
+        bail!("no targets sync to an MTP device or disk");
+    }
+
+    let mut journal = Journal::new(cfg);
+    let mut devices = Devices::default();
+    let mut failed = 0;
+    loop {
+        let arrived = devices.arrivals(&waiting, &mut journal);
+        if !arrived.is_empty() {
+            failed += sync_each(cfg, &arrived, &mut journal).await?;
+            waiting.retain(|t| !arrived.contains(&t.name));
+        }
+        if waiting.is_empty() {
+            break;
+        }
+        if !tick().await {
+            bail!("interrupted while waiting for {} targets", waiting.len());
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of the targets failed", failed);
+    }
+    Ok(())
+}
+
+/// Syncs the targets whose playlists changed since their last successful
+/// sync, and those whose entry differs from the one in `previous`, the
+/// config before it was reloaded. Targets whose device is known to be
+/// disconnected are left for when it shows up.
+async fn sync_changed(
+    cfg: &Config,
+    previous: Option<&Config>,
+    devices: &Devices,
+    journal: &mut Journal,
+) -> Result<()> {
+    let db = open_database(cfg)?;
+    let store = StateStore::new(cfg.state_dir());
+
+    let mut names = Vec::new();
+    for target in &cfg.targets {
+        let reconfigured = previous.is_some_and(|p| p.target(&target.name) != Some(target));
+        let plan = match Plan::for_target(&db, target) {
+            Ok(plan) => plan,
+            Err(e) => {
+                warn!("skipping {}: {:#}", target.name, e);
+                continue;
+            }
+        };
+        let state = store
+            .load(&target.name)
+            .with_context(|| format!("loading state for '{}'", target.name))?;
+        if reconfigured || state.selection.as_deref() != Some(plan.digest().as_str()) {
+            if devices.absent(&target.name) {
+                info!("{} changed; syncing once its device is connected", target.name);
+                continue;
+            }
+            names.push(target.name.clone());
+        }
+    }
+
+    if names.is_empty() {
+        debug!("No target's playlists changed");
+        return Ok(());
+    }
+    sync_each(cfg, &names, journal).await?;
+    Ok(())
+}
+
+/// Syncs each of the named targets, noting how it went in the journal, and
+/// returns how many failed.
+async fn sync_each(cfg: &Config, names: &[String], journal: &mut Journal) -> Result<usize> {
+    let db = open_database(cfg)?;
+    let mut failed = 0;
+    for name in names {
+        let Some(target) = cfg.target(name) else {
+            continue;
+        };
+        info!("------------- {} -------------", name);
+        journal.note(name, Activity::Syncing, None);
+        match targets::sync(&db, cfg, target, SyncOptions::default()).await {
+            Ok(()) => journal.note(name, Activity::Synced, None),
+            Err(e) => {
+                error!("{} failed: {:#}", name, e);
+                journal.note(name, Activity::Failed, Some(format!("{:#}", e)));
+                failed += 1;
+            }
+        }
+    }
+    commands::prune_caches(cfg)?;
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polls_for_writes() {
+        let path = std::env::temp_dir().join(format!("shittysync-poll-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut poll = Poll::new(vec![path.clone()]);
+        assert!(!poll.changed());
+
+        fs::write(&path, "playlists").unwrap();
+        assert!(poll.changed());
+        assert!(!poll.changed());
+        fs::write(&path, "more playlists").unwrap();
+        assert!(poll.changed());
+        fs::remove_file(&path).unwrap();
+        assert!(poll.changed());
+    }
+
+    #[test]
+    fn watches_disks_below_mount_roots() {
+        let dir = std::env::temp_dir().join(format!("shittysync-disk-{}", std::process::id()));
+        let usb = format!("/Volumes/shittysync-{}/Music", std::process::id());
+        let cfg: Config = toml::from_str(&format!(
+            r#"
+            basepath = "/music"
+            statedir = "{state}"
+
+            [swinsian]
+            dbpath = "/db.sqlite"
+
+            [[target]]
+            name = "car"
+            kind = "disk"
+            destination = "{usb}"
+            playlistfolder = "{usb}/Playlists"
+
+            [[target]]
+            name = "stick"
+            kind = "rsync"
+            destination = "/media/stick/Music"
+
+            [[target]]
+            name = "nas"
+            kind = "disk"
+            destination = "nas:/music"
+            playlistfolder = "nas:/music/Playlists"
+
+            [[target]]
+            name = "backup"
+            kind = "rsync"
+            destination = "{backup}"
+            "#,
+            state = dir.join("state").display(),
+            backup = dir.join("backup").display(),
+        ))
+        .unwrap();
+        let targets: Vec<&Target> = cfg.targets.iter().filter(|t| removable(t)).collect();
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["car", "stick"]);
+
+        let mut journal = Journal::new(&cfg);
+        let mut devices = Devices::default();
+        assert!(devices.arrivals(&targets, &mut journal).is_empty());
+        assert!(devices.absent("car"));
+
+        let status = fs::read_to_string(dir.join("state/shared/status.json")).unwrap();
+        assert!(status.contains("\"waiting\""), "{}", status);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
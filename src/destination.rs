@@ -0,0 +1,132 @@
+use crate::error::Error;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Extensions of the files a mirroring target is allowed to delete.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aiff", "alac", "ape", "dsf", "flac", "m4a", "mp3", "mp4", "ogg", "opus", "wav",
+    "wma", "wv",
+];
+
+/// A location written to by rsync: either a local directory or, following
+/// rsync's own rule, a remote `host:path` reached over ssh when a `:` appears
+/// before the first `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Local(PathBuf),
+    Remote { host: String, path: String },
+}
+
+impl Destination {
+    pub fn parse(location: &str) -> Destination {
+        match location.split_once(':') {
+            Some((host, path)) if !host.is_empty() && !host.contains('/') => Destination::Remote {
+                host: host.to_string(),
+                path: path.to_string(),
+            },
+            _ => Destination::Local(PathBuf::from(location)),
+        }
+    }
+
+    /// Deletes `files`, given relative to this destination. Files that are
+    /// already gone are ignored.
+    pub async fn remove(&self, files: &[String]) -> Result<(), Error> {
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        match self {
+            Destination::Local(root) => {
+                for file in files {
+                    match tokio::fs::remove_file(root.join(file)).await {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+            Destination::Remote { host, path } => {
+                let mut child = Command::new("ssh")
+                    .arg(host)
+                    .arg(format!("cd {} && xargs -0 rm -f --", shell_quote(path)))
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+
+                let mut stdin = child.stdin.take().ok_or(Error::CouldNotGetStdin)?;
+                stdin.write_all(files.join("\0").as_bytes()).await?;
+                drop(stdin);
+
+                let output = child.wait_with_output().await?;
+                if !output.status.success() {
+                    return Err(Error::RemoteDelete(
+                        host.clone(),
+                        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Joins a path relative to `root` onto it with exactly one separator.
+pub fn join(root: &str, relative: &str) -> String {
+    format!("{}/{}", root.trim_end_matches('/'), relative.trim_start_matches('/'))
+}
+
+/// The inverse of [`join`]: `path` relative to `root`, if it lies beneath it.
+pub fn strip_root<'a>(root: &str, path: &'a str) -> Option<&'a str> {
+    path.strip_prefix(root.trim_end_matches('/'))?.strip_prefix('/')
+}
+
+/// Whether `path` has one of the [`AUDIO_EXTENSIONS`].
+pub fn is_audio(path: &str) -> bool {
+    path.rsplit_once('.')
+        .map(|(_, ext)| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Quotes `s` for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_local_and_remote_destinations() {
+        assert_eq!(
+            Destination::parse("nas:/media/Solo/"),
+            Destination::Remote {
+                host: "nas".into(),
+                path: "/media/Solo/".into()
+            }
+        );
+        assert_eq!(
+            Destination::parse("/Volumes/USB/a:b"),
+            Destination::Local(PathBuf::from("/Volumes/USB/a:b"))
+        );
+    }
+
+    #[test]
+    fn joins_and_strips_roots() {
+        assert_eq!(join("nas:/media/Solo/", "/A/one.flac"), "nas:/media/Solo/A/one.flac");
+        assert_eq!(join("/tmp/x", "A/one.flac"), "/tmp/x/A/one.flac");
+        assert_eq!(strip_root("/tmp/x/", "/tmp/x/A/one.flac"), Some("A/one.flac"));
+        assert_eq!(strip_root("/tmp/x", "/tmp/xy/one.flac"), None);
+    }
+
+    #[test]
+    fn only_audio_is_deletable() {
+        assert!(is_audio("A/one.FLAC"));
+        assert!(is_audio("A/two.m4a"));
+        assert!(!is_audio("A/cover.jpg"));
+        assert!(!is_audio("README"));
+    }
+}
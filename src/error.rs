@@ -1,3 +1,4 @@
+use crate::copier::FileError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -8,6 +9,9 @@ pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error(transparent)]
+    State(#[from] serde_json::Error),
+
     #[error(transparent)]
     Mtp(#[from] libmtp_rs::error::Error),
 
@@ -29,15 +33,95 @@ pub enum Error {
     #[error("transcoder: could not generate output filename for `{0}`")]
     TranscodeCouldNotGenerateOutputFilename(String),
 
-    #[error("ffmpeg failed to transcode `{0}`")]
-    FFmpeg(String),
+    #[error("ffmpeg failed to transcode `{0}`: {1}")]
+    FFmpeg(String, String),
+
+    #[error("could not measure the loudness of `{0}`: {1}")]
+    Analyse(String, String),
+
+    #[error("could not decode `{0}`: {1}")]
+    Decode(String, String),
+
+    #[error(transparent)]
+    Wav(#[from] hound::Error),
+
+    #[error("transcoding profile `{profile}` isn't supported by the {backend} encoder")]
+    UnsupportedProfile {
+        profile: String,
+        backend: &'static str,
+    },
+
+    #[error("ffprobe failed to inspect `{0}`")]
+    FFprobe(String),
 
     #[error("watch has no usable storage")]
     NoWatchStorage,
 
+    #[error("could not find file on the watch: `{0}`")]
+    CouldNotFindFile(String),
+
     #[error("could not find folder: `{0}`")]
     CouldNotFindFolder(String),
 
+    #[error("could not list files on `{0}`: {1}")]
+    List(String, String),
+
+    #[error("only part of the files reached `{dest}`; {} failed", .failed.len())]
+    PartialTransfer {
+        dest: String,
+        failed: Vec<FileError>,
+    },
+
+    #[error("could not connect to `{dest}`: {message}")]
+    ConnectionRefused { dest: String, message: String },
+
+    #[error("permission denied on `{dest}`: {message}")]
+    PermissionDenied {
+        dest: String,
+        message: String,
+        failed: Vec<FileError>,
+    },
+
+    #[error("no space left on `{dest}`")]
+    DiskFull {
+        dest: String,
+        failed: Vec<FileError>,
+    },
+
+    #[error("rsync to `{dest}` failed with {status}: {message}")]
+    Rsync {
+        dest: String,
+        status: String,
+        message: String,
+    },
+
+    #[error("target `{target}` copies natively, but `{destination}` isn't a local path")]
+    NativeRemote { target: String, destination: String },
+
+    #[error("could not delete files on `{0}`: {1}")]
+    RemoteDelete(String, String),
+
+    #[error("invalid size `{0}`, expected e.g. `512M` or `20G`")]
+    InvalidSize(String),
+
+    #[error("target `{0}` is defined more than once")]
+    DuplicateTarget(String),
+
+    #[error("target `{target}` uses unknown transcoding profile `{profile}`")]
+    UnknownProfile { target: String, profile: String },
+
     #[error("mDNS discovery is already running")]
     DiscoveryAlreadyRunning,
 }
+
+impl Error {
+    /// The files a failed copy named as not having made it.
+    pub fn failed_files(&self) -> &[FileError] {
+        match self {
+            Error::PartialTransfer { failed, .. }
+            | Error::PermissionDenied { failed, .. }
+            | Error::DiskFull { failed, .. } => failed,
+            _ => &[],
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Making destination paths acceptable to the filesystem a target is
+//! formatted with, e.g. a FAT32 USB stick for the car, and consistently
+//! normalised for the players reading them.
+
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters FAT32 and exFAT (and Windows reading either) refuse in names.
+const FORBIDDEN: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, whatever the extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Longest name, in UTF-16 code units, FAT32 and exFAT allow.
+const MAX_NAME: usize = 255;
+
+/// Longest path, in UTF-16 code units, below the destination root; leaves
+/// room for a mount point within Windows' 260-character limit.
+const MAX_PATH: usize = 240;
+
+/// The filesystem a destination is formatted with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    /// Anything goes, as on ext4 or APFS; paths are used as they are.
+    #[default]
+    Posix,
+    Fat32,
+    Exfat,
+}
+
+impl Filesystem {
+    /// The largest file the filesystem can hold.
+    pub fn max_file_size(self) -> Option<u64> {
+        match self {
+            Filesystem::Fat32 => Some(u32::MAX as u64),
+            Filesystem::Posix | Filesystem::Exfat => None,
+        }
+    }
+
+    /// Rewrites each component of the relative `path` into a name the
+    /// filesystem accepts, and shortens the file name if the whole path is
+    /// too long. The result only depends on `path`.
+    pub fn sanitise(self, path: &str) -> String {
+        if self == Filesystem::Posix {
+            return path.to_string();
+        }
+
+        let components: Vec<String> = path
+            .split('/')
+            .map(|c| if c.is_empty() { String::new() } else { sanitise_name(c) })
+            .collect();
+        let sanitised = components.join("/");
+
+        let overflow = utf16_len(sanitised.trim_start_matches('/')).saturating_sub(MAX_PATH);
+        if overflow == 0 {
+            return sanitised;
+        }
+        let (folder, name) = sanitised.rsplit_once('/').unwrap_or(("", &sanitised));
+        let (stem, extension) = split_extension(name);
+        let keep = utf16_len(stem).saturating_sub(overflow + 8).max(8);
+        let shortened = with_suffix(&truncate_utf16(stem, keep), extension, &hash(path));
+        if folder.is_empty() && !sanitised.starts_with('/') {
+            shortened
+        } else {
+            format!("{}/{}", folder, shortened)
+        }
+    }
+
+    /// The form of `path` that two names which the filesystem treats as the
+    /// same share. FAT32 and exFAT ignore case.
+    pub fn collision_key(self, path: &str) -> String {
+        match self {
+            Filesystem::Posix => path.to_string(),
+            Filesystem::Fat32 | Filesystem::Exfat => path.to_lowercase(),
+        }
+    }
+}
+
+/// The Unicode normalisation form names are written in on a destination.
+///
+/// macOS hands out decomposed (NFD) names for files created through Finder,
+/// while Linux and most players compare names byte for byte and playlists
+/// are usually typed or tagged in composed form (NFC). A name that looks the
+/// same can then fail to match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalisation {
+    /// Names as the library has them.
+    #[default]
+    Preserve,
+    Nfc,
+    Nfd,
+}
+
+impl Normalisation {
+    pub fn apply(self, path: &str) -> String {
+        match self {
+            Normalisation::Preserve => path.to_string(),
+            Normalisation::Nfc => path.nfc().collect(),
+            Normalisation::Nfd => path.nfd().collect(),
+        }
+    }
+}
+
+/// The form two paths that only differ in normalisation share.
+pub fn normalisation_key(path: &str) -> String {
+    path.nfc().collect()
+}
+
+/// `path` with a short hash of `source` added to its file name, to tell it
+/// apart from another song that ended up with the same name.
+pub fn disambiguate(path: &str, source: &str) -> String {
+    let (folder, name) = match path.rsplit_once('/') {
+        Some((folder, name)) => (Some(folder), name),
+        None => (None, path),
+    };
+    let (stem, extension) = split_extension(name);
+    let name = with_suffix(stem, extension, &hash(source));
+    match folder {
+        Some(folder) => format!("{}/{}", folder, name),
+        None => name,
+    }
+}
+
+fn sanitise_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| if c < ' ' || FORBIDDEN.contains(&c) { '_' } else { c })
+        .collect();
+    // Windows silently drops trailing dots and spaces, so names ending in
+    // them can't be opened again.
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+    if name.is_empty() {
+        name.push('_');
+    }
+
+    let (stem, extension) = split_extension(&name);
+    let reserved = RESERVED.iter().any(|r| stem.eq_ignore_ascii_case(r));
+    let stem = if reserved { format!("{}_", stem) } else { stem.to_string() };
+
+    let room = MAX_NAME - extension.map_or(0, |e| utf16_len(e) + 1);
+    let stem = truncate_utf16(&stem, room);
+    match extension {
+        Some(extension) => format!("{}.{}", stem, extension),
+        None => stem,
+    }
+}
+
+fn split_extension(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    }
+}
+
+fn with_suffix(stem: &str, extension: Option<&str>, suffix: &str) -> String {
+    match extension {
+        Some(extension) => format!("{} ~{}.{}", stem, suffix, extension),
+        None => format!("{} ~{}", stem, suffix),
+    }
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+/// The longest prefix of `s` that's at most `max` UTF-16 code units long.
+fn truncate_utf16(s: &str, max: usize) -> String {
+    let mut len = 0;
+    s.chars()
+        .take_while(|c| {
+            len += c.len_utf16();
+            len <= max
+        })
+        .collect()
+}
+
+fn hash(s: &str) -> String {
+    Sha3_256::digest(s.as_bytes())[..3]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitises_names_for_fat() {
+        let fat = Filesystem::Fat32;
+        assert_eq!(
+            fat.sanitise("/AC:DC/Who Made Who?/01 \"Ride On\".mp3"),
+            "/AC_DC/Who Made Who_/01 _Ride On_.mp3"
+        );
+        assert_eq!(
+            fat.sanitise("Various Artists/Hits Vol. 2.../aux.flac"),
+            "Various Artists/Hits Vol. 2/aux_.flac"
+        );
+        assert_eq!(Filesystem::Posix.sanitise("A:B/c?.mp3"), "A:B/c?.mp3");
+
+        let long = format!("Artist/{}.flac", "x".repeat(300));
+        let sanitised = fat.sanitise(&long);
+        assert!(utf16_len(&sanitised) <= MAX_PATH, "{}", sanitised);
+        assert!(sanitised.starts_with("Artist/xxx") && sanitised.ends_with(".flac"));
+        assert_eq!(sanitised, fat.sanitise(&long));
+    }
+
+    #[test]
+    fn disambiguates_with_the_source() {
+        let a = disambiguate("Artist/Song.mp3", "/music/Artist/Song?.mp3");
+        let b = disambiguate("Artist/Song.mp3", "/music/Artist/Song*.mp3");
+        assert!(a.starts_with("Artist/Song ~") && a.ends_with(".mp3"));
+        assert_ne!(a, b);
+        assert_eq!(
+            Filesystem::Exfat.collision_key("Artist/SONG.mp3"),
+            Filesystem::Exfat.collision_key("artist/song.MP3")
+        );
+    }
+
+    #[test]
+    fn normalises_names() {
+        let decomposed = "Bjo\u{308}rk/Jo\u{301}ga.flac";
+        let composed = "Bj\u{f6}rk/J\u{f3}ga.flac";
+        assert_eq!(Normalisation::Nfc.apply(decomposed), composed);
+        assert_eq!(Normalisation::Nfd.apply(composed), decomposed);
+        assert_eq!(Normalisation::Preserve.apply(decomposed), decomposed);
+        assert_ne!(decomposed, composed);
+        assert_eq!(normalisation_key(decomposed), normalisation_key(composed));
+    }
+}
@@ -0,0 +1,389 @@
+//! EBU R128 loudness analysis and the ReplayGain adjustments derived from it.
+//!
+//! Measurements are cached in the state directory, keyed by source path and
+//! invalidated when the source's size or modification time changes, so each
+//! song is only analysed once however many targets use it.
+
+use crate::error::Error;
+use crate::state::Fingerprint;
+use crate::transcode::Encoder;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use swinsiandb::Track;
+
+/// The level gains aim for, as in ReplayGain 2.0.
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+/// How a profile evens out loudness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GainMode {
+    /// Every song on its own.
+    Track,
+    /// Songs of one album together, keeping their relative levels.
+    Album,
+}
+
+/// A song's measured loudness.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// True peak in dBTP.
+    pub peak: f64,
+}
+
+impl Loudness {
+    /// The adjustment in dB that brings this to [`REFERENCE_LUFS`].
+    pub fn gain(&self) -> f64 {
+        REFERENCE_LUFS - self.integrated
+    }
+
+    /// The peak as a linear sample value, as ReplayGain peak tags expect.
+    pub fn linear_peak(&self) -> f64 {
+        10f64.powf(self.peak / 20.0)
+    }
+}
+
+/// The loudness adjustment for one song.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gain {
+    pub track: Loudness,
+    pub album: Option<Loudness>,
+}
+
+impl Gain {
+    /// The adjustment in dB for `mode`, falling back to the track's when the
+    /// album's is unknown.
+    pub fn db(&self, mode: GainMode) -> f64 {
+        match (mode, self.album) {
+            (GainMode::Album, Some(album)) => album.gain(),
+            _ => self.track.gain(),
+        }
+    }
+
+    /// ReplayGain tags describing this adjustment.
+    pub fn tags(&self) -> Vec<(&'static str, String)> {
+        let mut tags = vec![
+            ("REPLAYGAIN_TRACK_GAIN", format!("{:.2} dB", self.track.gain())),
+            ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", self.track.linear_peak())),
+        ];
+        if let Some(album) = self.album {
+            tags.push(("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", album.gain())));
+            tags.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", album.linear_peak())));
+        }
+        tags
+    }
+}
+
+/// Cached measurements, keyed by full source path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoudnessCache {
+    pub files: BTreeMap<String, LoudnessRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoudnessRecord {
+    pub size: u64,
+    pub mtime: u64,
+    #[serde(flatten)]
+    pub loudness: Loudness,
+}
+
+impl LoudnessCache {
+    /// The measurement for `source`, if it was taken of the file as it is now.
+    pub fn get(&self, source: &str) -> Option<Loudness> {
+        let record = self.files.get(source)?;
+        let fp = Fingerprint::of(Path::new(source)).ok()?;
+        (record.size == fp.size && record.mtime == fp.mtime).then_some(record.loudness)
+    }
+
+    /// Measures every one of `sources` the cache doesn't know yet with
+    /// `encoder`, in parallel. Songs that can't be analysed are logged and
+    /// left out.
+    pub fn analyse(&mut self, encoder: &dyn Encoder, sources: &[&str]) {
+        let missing: Vec<&str> = sources
+            .iter()
+            .copied()
+            .filter(|s| self.get(s).is_none())
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+
+        info!("Analysing loudness of {} files", missing.len());
+        let measured: Vec<(&str, Fingerprint, Loudness)> = missing
+            .par_iter()
+            .filter_map(|source| {
+                let path = Path::new(source);
+                let result = Fingerprint::of(path)
+                    .map_err(Error::from)
+                    .and_then(|fp| Ok((fp, encoder.analyse(path)?)));
+                match result {
+                    Ok((fp, loudness)) => Some((*source, fp, loudness)),
+                    Err(e) => {
+                        warn!("could not analyse {}: {}", source, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        for (source, fp, loudness) in measured {
+            let record = LoudnessRecord {
+                size: fp.size,
+                mtime: fp.mtime,
+                loudness,
+            };
+            self.files.insert(source.to_string(), record);
+        }
+    }
+
+    /// Gains for every one of `tracks` that has a measurement. Album values
+    /// are combined from the album's songs among `tracks`, weighted by length.
+    pub fn gains(&self, tracks: &[&Track]) -> HashMap<String, Gain> {
+        let mut albums: HashMap<(String, String), Vec<(Loudness, f64)>> = HashMap::new();
+        for track in tracks {
+            if let (Some(key), Some(loudness)) = (album_key(track), self.get(&track.path)) {
+                let length = track.length.unwrap_or(1.0).max(1.0);
+                albums.entry(key).or_default().push((loudness, length));
+            }
+        }
+        let albums: HashMap<_, _> = albums
+            .into_iter()
+            .map(|(key, songs)| (key, combine(&songs)))
+            .collect();
+
+        tracks
+            .iter()
+            .filter_map(|track| {
+                let gain = Gain {
+                    track: self.get(&track.path)?,
+                    album: album_key(track).and_then(|k| albums.get(&k).copied()),
+                };
+                Some((track.path.clone(), gain))
+            })
+            .collect()
+    }
+}
+
+fn album_key(track: &Track) -> Option<(String, String)> {
+    let album = track.album.clone().filter(|a| !a.trim().is_empty())?;
+    let artist = track
+        .albumartist
+        .clone()
+        .or_else(|| track.artist.clone())
+        .unwrap_or_default();
+    Some((artist, album))
+}
+
+/// The loudness of songs played back to back: their energy averaged by
+/// length, and the highest peak.
+pub fn combine(songs: &[(Loudness, f64)]) -> Loudness {
+    let total: f64 = songs.iter().map(|(_, length)| length).sum();
+    let energy: f64 = songs
+        .iter()
+        .map(|(l, length)| length * 10f64.powf(l.integrated / 10.0))
+        .sum();
+    Loudness {
+        integrated: 10.0 * (energy / total).log10(),
+        peak: songs
+            .iter()
+            .map(|(l, _)| l.peak)
+            .fold(f64::NEG_INFINITY, f64::max),
+    }
+}
+
+/// A direct form II transposed biquad.
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The two stages of ITU-R BS.1770's K-weighting at `rate`, derived the way
+/// libebur128 does so that any sample rate works.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(rate);
+
+    let k = (std::f64::consts::PI * 1681.974450955533 / rate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let k = (std::f64::consts::PI * 38.13547087602444 / rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, highpass]
+}
+
+/// Measures integrated loudness as EBU R128 defines it, for backends that
+/// decode audio themselves. Every channel is weighted equally and the peak is
+/// the sample peak, so results can differ slightly from ffmpeg's on surround
+/// or heavily clipped material.
+pub struct Meter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    /// Samples per channel in 100ms.
+    step: usize,
+    /// Summed weighted energy of each complete 100ms stretch.
+    energies: Vec<f64>,
+    current: f64,
+    frames: usize,
+    peak: f64,
+}
+
+impl Meter {
+    pub fn new(channels: usize, rate: u32) -> Meter {
+        Meter {
+            channels,
+            filters: vec![k_weighting(rate); channels],
+            step: (rate as usize / 10).max(1),
+            energies: Vec::new(),
+            current: 0.0,
+            frames: 0,
+            peak: 0.0,
+        }
+    }
+
+    /// Feeds interleaved samples in the range -1.0..=1.0.
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (sample, filters) in frame.iter().zip(&mut self.filters) {
+                let x = f64::from(*sample);
+                self.peak = self.peak.max(x.abs());
+                let shelved = filters[0].process(x);
+                let y = filters[1].process(shelved);
+                self.current += y * y;
+            }
+            self.frames += 1;
+            if self.frames == self.step {
+                self.energies.push(self.current);
+                self.current = 0.0;
+                self.frames = 0;
+            }
+        }
+    }
+
+    /// The gated loudness of everything fed so far, or `None` if there was
+    /// less than 400ms of audio above the silence gate.
+    pub fn finish(self) -> Option<Loudness> {
+        const ABSOLUTE_GATE: f64 = -70.0;
+        let lufs = |energy: f64| -0.691 + 10.0 * energy.log10();
+
+        // 400ms blocks overlapping by 75%.
+        let blocks: Vec<f64> = self
+            .energies
+            .windows(4)
+            .map(|w| w.iter().sum::<f64>() / (4 * self.step) as f64)
+            .filter(|&e| lufs(e) > ABSOLUTE_GATE)
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+        let relative_gate = lufs(mean(&blocks)) - 10.0;
+        let gated: Vec<f64> = blocks.into_iter().filter(|&e| lufs(e) > relative_gate).collect();
+        Some(Loudness {
+            integrated: lufs(mean(&gated)),
+            peak: 20.0 * self.peak.max(1e-5).log10(),
+        })
+    }
+}
+
+/// Reads the summary ffmpeg's `ebur128` filter prints at the end of a run.
+pub fn parse_ebur128(output: &str) -> Option<Loudness> {
+    let summary = &output[output.rfind("Summary:")?..];
+    let value = |label: &str| -> Option<f64> {
+        let line = summary.lines().find(|l| l.trim_start().starts_with(label))?;
+        line.trim_start()[label.len()..].split_whitespace().next()?.parse().ok()
+    };
+    Some(Loudness {
+        integrated: value("I:")?,
+        peak: value("Peak:")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ebur128_summary() {
+        let output = "\
+[Parsed_ebur128_0 @ 0x600] Summary:
+
+  Integrated loudness:
+    I:         -11.4 LUFS
+    Threshold: -21.6 LUFS
+
+  Loudness range:
+    LRA:         4.2 LU
+
+  True peak:
+    Peak:        0.6 dBFS
+";
+        let loudness = parse_ebur128(output).unwrap();
+        assert_eq!(loudness, Loudness { integrated: -11.4, peak: 0.6 });
+        assert!((loudness.gain() - -6.6).abs() < 1e-9);
+        assert_eq!(parse_ebur128("no summary here"), None);
+    }
+
+    #[test]
+    fn combines_album_loudness_by_energy() {
+        let quiet = Loudness { integrated: -20.0, peak: -3.0 };
+        let loud = Loudness { integrated: -10.0, peak: -0.5 };
+        let album = combine(&[(quiet, 100.0), (loud, 100.0)]);
+        // Dominated by the loud half: 10*log10((0.01 + 0.1) / 2).
+        assert!((album.integrated - -12.596).abs() < 1e-3);
+        assert_eq!(album.peak, -0.5);
+    }
+
+    #[test]
+    fn meters_a_sine_at_its_reference_level() {
+        // BS.1770 is calibrated so that a 1kHz tone peaking at -20dBFS in one
+        // channel reads 3dB below that, and the 1kHz filter gain cancels out.
+        let rate = 48000;
+        let samples: Vec<f32> = (0..rate * 5)
+            .map(|i| 0.1 * (2.0 * std::f32::consts::PI * 997.0 * i as f32 / rate as f32).sin())
+            .collect();
+
+        let mut meter = Meter::new(1, rate);
+        meter.add(&samples);
+        let loudness = meter.finish().unwrap();
+        assert!((loudness.integrated - -23.0).abs() < 0.1, "{:?}", loudness);
+        assert!((loudness.peak - -20.0).abs() < 0.01, "{:?}", loudness);
+
+        let silence = Meter::new(2, rate);
+        assert_eq!(silence.finish(), None);
+    }
+}
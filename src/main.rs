@@ -1,10 +1,19 @@
+mod artwork;
+mod cache;
 mod cli;
 mod commands;
 mod config;
+mod copier;
+mod daemon;
+mod destination;
 mod error;
 mod evermusic;
-mod m3u;
+mod filesystem;
+mod loudness;
+mod playlist;
 mod rsync;
+mod state;
+mod targets;
 mod transcode;
 mod watch;
 
@@ -13,9 +22,10 @@ extern crate log;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use cli::Args;
+use cli::{Args, Command};
 use config::Config;
 use std::path::Path;
+use std::time::Duration;
 use swinsiandb::Database;
 
 #[tokio::main]
@@ -27,26 +37,47 @@ async fn main() -> Result<()> {
 
     let cfg = Config::load_config(&args.config)
         .with_context(|| format!("loading config from {}", args.config.display()))?;
-    let db = Database::from_file(Path::new(&cfg.swinsian.dbpath))
-        .context("opening Swinsian database")?;
 
-    if args.deck {
-        commands::sync_deck(&db, &cfg).await?;
-    }
-    if args.disk {
-        commands::sync_disk(&db, &cfg).await?;
-    }
-    if args.phone {
-        commands::sync_phone(&db, &cfg).await?;
-    }
-    if args.watch {
-        commands::sync_watch(&db, &cfg).await?;
+    match &args.command {
+        Command::Sync {
+            targets,
+            dry_run,
+            files,
+            prune,
+            rescan,
+            ..
+        } => {
+            let opts = targets::SyncOptions {
+                dry_run: *dry_run,
+                list_files: *files,
+                prune: *prune,
+                rescan: *rescan,
+            };
+            commands::sync(&open_database(&cfg)?, &cfg, targets, opts).await?
+        }
+        Command::Daemon { debounce } => {
+            daemon::run(&args.config, cfg.clone(), Duration::from_secs(*debounce)).await?
+        }
+        Command::Wait { targets } => daemon::wait(&cfg, targets).await?,
+        Command::Verify { targets, repair } => {
+            commands::verify(&open_database(&cfg)?, &cfg, targets, *repair).await?
+        }
+        Command::Status { targets } => commands::status(&cfg, targets)?,
+        Command::History { targets, limit } => commands::history(&cfg, targets, *limit)?,
+        Command::Loudness { targets, tracks } => {
+            commands::loudness(&open_database(&cfg)?, &cfg, targets, *tracks)?
+        }
+        Command::Cache { action } => commands::cache(&cfg, action)?,
     }
 
     info!("------------- DONE -------------");
     Ok(())
 }
 
+fn open_database(cfg: &Config) -> Result<Database> {
+    Database::from_file(Path::new(&cfg.swinsian.dbpath)).context("opening Swinsian database")
+}
+
 fn init_logging() {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
@@ -0,0 +1,81 @@
+use super::{single_line, Document, PerPlaylist};
+
+/// Extended M3U: a `#PLAYLIST` title and an `#EXTINF` line (duration, artist
+/// and title) per entry. Unknown durations are written as `-1`, as the format
+/// specifies. The contents are always UTF-8; with `utf8` the file gets the
+/// `.m3u8` extension, which is what tells players to read them that way.
+pub struct M3u {
+    pub utf8: bool,
+}
+
+impl PerPlaylist for M3u {
+    fn extension(&self) -> &'static str {
+        if self.utf8 {
+            "m3u8"
+        } else {
+            "m3u"
+        }
+    }
+
+    fn render_one(&self, playlist: &Document) -> String {
+        let mut out = format!("#EXTM3U\n#PLAYLIST:{}\n", single_line(&playlist.name));
+        for entry in &playlist.entries {
+            let duration = entry.duration.map_or(-1, |d| d.round() as i64);
+            out.push_str(&format!(
+                "#EXTINF:{},{}\n{}\n",
+                duration,
+                single_line(&entry.display()),
+                entry.path
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Entry, PlaylistWriter};
+    use super::*;
+
+    fn entry(
+        path: &str,
+        duration: Option<f64>,
+        artist: Option<&str>,
+        title: Option<&str>,
+    ) -> Entry {
+        Entry {
+            path: path.to_string(),
+            source: format!("/music{}", path),
+            duration,
+            artist: artist.map(String::from),
+            title: title.map(String::from),
+            album: None,
+        }
+    }
+
+    #[test]
+    fn renders_extended_m3u() {
+        let playlist = Document {
+            name: "Über\nMix".to_string(),
+            folder_path: "Über Mix".to_string(),
+            entries: vec![
+                entry("/Orb/06 Sentinel.flac", Some(412.6), Some("The Orb"), Some("Sentinel")),
+                entry("/Björk/Jóga.flac", None, None, Some("Jóga")),
+                entry("/Unknown/03 Untitled.mp3", Some(60.0), None, None),
+            ],
+        };
+        let rendered = M3u { utf8: true }.render(&[playlist]);
+        assert!(rendered[0].file_name.ends_with(".m3u8"));
+        assert_eq!(
+            rendered[0].contents,
+            "#EXTM3U\n\
+             #PLAYLIST:Über Mix\n\
+             #EXTINF:413,The Orb - Sentinel\n\
+             /Orb/06 Sentinel.flac\n\
+             #EXTINF:-1,Jóga\n\
+             /Björk/Jóga.flac\n\
+             #EXTINF:60,03 Untitled\n\
+             /Unknown/03 Untitled.mp3\n"
+        );
+    }
+}
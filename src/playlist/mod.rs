@@ -0,0 +1,228 @@
+//! Playlist file formats. Each format is a [`PlaylistWriter`]; targets pick
+//! the ones they want with `formats = [...]`.
+
+mod m3u;
+mod pls;
+mod rekordbox;
+mod xspf;
+
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use swinsiandb::Track;
+
+pub use m3u::M3u;
+pub use pls::Pls;
+pub use rekordbox::Rekordbox;
+pub use xspf::Xspf;
+
+/// One playlist entry: where the file is, plus the metadata players show for
+/// it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The file as referenced from the playlist on the destination.
+    pub path: String,
+    /// The file's full path in the source library.
+    pub source: String,
+    /// Length in seconds, if known.
+    pub duration: Option<f64>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+}
+
+impl Entry {
+    /// Builds an entry for `track`, referenced in the playlist as `path`.
+    pub fn new(track: &Track, path: String) -> Entry {
+        Entry {
+            path,
+            source: track.path.clone(),
+            duration: track.length,
+            artist: track.artist.clone(),
+            title: track.title.clone(),
+            album: track.album.clone(),
+        }
+    }
+
+    /// A one-line description: `Artist - Title`, or whichever of the two is
+    /// known, falling back to the file name.
+    pub fn display(&self) -> String {
+        match (nonempty(&self.artist), nonempty(&self.title)) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.to_string(),
+            (Some(artist), None) => artist.to_string(),
+            (None, None) => {
+                let name = self.path.rsplit('/').next().unwrap_or(&self.path);
+                name.rsplit_once('.').map_or(name, |(stem, _)| stem).to_string()
+            }
+        }
+    }
+}
+
+/// A playlist ready to be written out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub name: String,
+    /// The playlist's Swinsian folder path, including its own name, e.g.
+    /// `Weatherall/Sabresonic`.
+    pub folder_path: String,
+    pub entries: Vec<Entry>,
+}
+
+/// A rendered playlist file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendered {
+    pub file_name: String,
+    pub contents: String,
+}
+
+/// Turns playlists into files. Most formats write one file per playlist (see
+/// [`PerPlaylist`]); collection formats such as Rekordbox XML write a single
+/// file covering all of them.
+pub trait PlaylistWriter {
+    fn render(&self, playlists: &[Document]) -> Vec<Rendered>;
+}
+
+/// A format with one file per playlist.
+pub trait PerPlaylist {
+    fn extension(&self) -> &'static str;
+    fn render_one(&self, playlist: &Document) -> String;
+}
+
+impl<T: PerPlaylist> PlaylistWriter for T {
+    fn render(&self, playlists: &[Document]) -> Vec<Rendered> {
+        playlists
+            .iter()
+            .map(|p| Rendered {
+                file_name: format!("{}.{}", filenamify::filenamify(&p.name), self.extension()),
+                contents: self.render_one(p),
+            })
+            .collect()
+    }
+}
+
+/// The playlist formats a target can be configured with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistFormat {
+    /// Extended M3U.
+    M3u,
+    /// Extended M3U with a `.m3u8` extension, so players read it as UTF-8.
+    M3u8,
+    Pls,
+    Xspf,
+    /// A single `rekordbox.xml` collection with every playlist in its
+    /// Swinsian folder hierarchy, referencing the source files.
+    Rekordbox,
+}
+
+impl PlaylistFormat {
+    pub fn writer(self) -> Box<dyn PlaylistWriter> {
+        match self {
+            PlaylistFormat::M3u => Box::new(M3u { utf8: false }),
+            PlaylistFormat::M3u8 => Box::new(M3u { utf8: true }),
+            PlaylistFormat::Pls => Box::new(Pls),
+            PlaylistFormat::Xspf => Box::new(Xspf),
+            PlaylistFormat::Rekordbox => Box::new(Rekordbox),
+        }
+    }
+}
+
+/// The formats used when a target doesn't configure any.
+pub fn default_formats() -> Vec<PlaylistFormat> {
+    vec![PlaylistFormat::M3u]
+}
+
+/// Renders `playlists` in each of `formats`.
+pub fn render_all(formats: &[PlaylistFormat], playlists: &[Document]) -> Vec<Rendered> {
+    formats
+        .iter()
+        .flat_map(|f| f.writer().render(playlists))
+        .collect()
+}
+
+/// Writes `files` into a fresh temporary directory named after `target` and
+/// returns the directory, ready to be synced as a whole.
+pub async fn write_temp(target: &str, files: &[Rendered]) -> Result<PathBuf, Error> {
+    let dir = std::env::temp_dir().join(format!(
+        "shittysync-playlists-{}",
+        filenamify::filenamify(target)
+    ));
+    match tokio::fs::remove_dir_all(&dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    tokio::fs::create_dir_all(&dir).await?;
+
+    for file in files {
+        tokio::fs::write(dir.join(&file.file_name), &file.contents).await?;
+    }
+    Ok(dir)
+}
+
+/// Whether `file` looks like a playlist written by one of the formats.
+pub fn is_playlist(file: &str) -> bool {
+    file == rekordbox::FILE_NAME
+        || [".m3u", ".m3u8", ".pls", ".xspf"]
+            .iter()
+            .any(|ext| file.ends_with(ext))
+}
+
+fn nonempty(s: &Option<String>) -> Option<&str> {
+    s.as_deref().filter(|s| !s.trim().is_empty())
+}
+
+/// Replaces line breaks, which would end a line-based directive early.
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+/// Escapes text for use in XML content and attribute values.
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a path for use in a URI, leaving `/` and unreserved
+/// characters alone.
+fn percent_encode(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_uris_and_xml() {
+        assert_eq!(percent_encode("/A B/Jóga.flac"), "/A%20B/J%C3%B3ga.flac");
+        assert_eq!(xml_escape(r#"Tom & "Jerry" <3"#), "Tom &amp; &quot;Jerry&quot; &lt;3");
+    }
+
+    #[test]
+    fn recognises_playlist_files() {
+        assert!(is_playlist("Running.m3u8"));
+        assert!(is_playlist("Running.xspf"));
+        assert!(is_playlist("rekordbox.xml"));
+        assert!(!is_playlist("Running.flac"));
+    }
+}
@@ -0,0 +1,26 @@
+use super::{single_line, Document, PerPlaylist};
+
+/// PLS, as understood by older network streamers and internet radio players.
+pub struct Pls;
+
+impl PerPlaylist for Pls {
+    fn extension(&self) -> &'static str {
+        "pls"
+    }
+
+    fn render_one(&self, playlist: &Document) -> String {
+        let mut out = String::from("[playlist]\n");
+        for (i, entry) in playlist.entries.iter().enumerate() {
+            let n = i + 1;
+            let length = entry.duration.map_or(-1, |d| d.round() as i64);
+            out.push_str(&format!("File{}={}\n", n, entry.path));
+            out.push_str(&format!("Title{}={}\n", n, single_line(&entry.display())));
+            out.push_str(&format!("Length{}={}\n", n, length));
+        }
+        out.push_str(&format!(
+            "NumberOfEntries={}\nVersion=2\n",
+            playlist.entries.len()
+        ));
+        out
+    }
+}
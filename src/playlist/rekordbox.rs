@@ -0,0 +1,174 @@
+use super::{nonempty, percent_encode, xml_escape, Document, PlaylistWriter, Rendered};
+use std::collections::HashMap;
+
+pub const FILE_NAME: &str = "rekordbox.xml";
+
+/// A Rekordbox XML collection (`rekordbox.xml`) holding every playlist in its
+/// Swinsian folder hierarchy. Tracks reference the source library, since the
+/// file is meant to be imported into Rekordbox on the machine that has it.
+pub struct Rekordbox;
+
+/// A folder in the playlist tree, with children in first-seen order.
+#[derive(Default)]
+struct Folder<'a> {
+    folders: Vec<(&'a str, Folder<'a>)>,
+    playlists: Vec<&'a Document>,
+}
+
+impl<'a> Folder<'a> {
+    fn insert(&mut self, path: &[&'a str], playlist: &'a Document) {
+        match path.split_first() {
+            None => self.playlists.push(playlist),
+            Some((name, rest)) => {
+                let i = match self.folders.iter().position(|(n, _)| n == name) {
+                    Some(i) => i,
+                    None => {
+                        self.folders.push((name, Folder::default()));
+                        self.folders.len() - 1
+                    }
+                };
+                self.folders[i].1.insert(rest, playlist);
+            }
+        }
+    }
+
+    fn write(&self, name: &str, ids: &HashMap<&str, usize>, depth: usize, out: &mut String) {
+        let indent = "  ".repeat(depth);
+        out.push_str(&format!(
+            "{}<NODE Type=\"0\" Name=\"{}\" Count=\"{}\">\n",
+            indent,
+            xml_escape(name),
+            self.folders.len() + self.playlists.len()
+        ));
+        for (name, folder) in &self.folders {
+            folder.write(name, ids, depth + 1, out);
+        }
+        for playlist in &self.playlists {
+            out.push_str(&format!(
+                "{}  <NODE Name=\"{}\" Type=\"1\" KeyType=\"0\" Entries=\"{}\">\n",
+                indent,
+                xml_escape(&playlist.name),
+                playlist.entries.len()
+            ));
+            for entry in &playlist.entries {
+                out.push_str(&format!(
+                    "{}    <TRACK Key=\"{}\"/>\n",
+                    indent, ids[entry.source.as_str()]
+                ));
+            }
+            out.push_str(&format!("{}  </NODE>\n", indent));
+        }
+        out.push_str(&format!("{}</NODE>\n", indent));
+    }
+}
+
+impl PlaylistWriter for Rekordbox {
+    fn render(&self, playlists: &[Document]) -> Vec<Rendered> {
+        // Each source file appears once in the collection, numbered in order of
+        // first appearance.
+        let mut ids = HashMap::new();
+        let mut collection = String::new();
+        for entry in playlists.iter().flat_map(|p| &p.entries) {
+            if ids.contains_key(entry.source.as_str()) {
+                continue;
+            }
+            let id = ids.len() + 1;
+            ids.insert(entry.source.as_str(), id);
+
+            collection.push_str(&format!("    <TRACK TrackID=\"{}\"", id));
+            for (attr, value) in [
+                ("Name", &entry.title),
+                ("Artist", &entry.artist),
+                ("Album", &entry.album),
+            ] {
+                if let Some(value) = nonempty(value) {
+                    collection.push_str(&format!(" {}=\"{}\"", attr, xml_escape(value)));
+                }
+            }
+            if let Some(duration) = entry.duration {
+                collection.push_str(&format!(" TotalTime=\"{}\"", duration.round() as i64));
+            }
+            collection.push_str(&format!(
+                " Location=\"file://localhost{}\"/>\n",
+                xml_escape(&percent_encode(&entry.source))
+            ));
+        }
+
+        let mut root = Folder::default();
+        for playlist in playlists {
+            let mut path: Vec<&str> = playlist
+                .folder_path
+                .split('/')
+                .filter(|p| !p.is_empty())
+                .collect();
+            // The last component is the playlist itself.
+            path.pop();
+            root.insert(&path, playlist);
+        }
+
+        let mut out = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <DJ_PLAYLISTS Version=\"1.0.0\">\n\
+             \x20 <PRODUCT Name=\"{}\" Version=\"{}\" Company=\"\"/>\n\
+             \x20 <COLLECTION Entries=\"{}\">\n",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            ids.len()
+        );
+        out.push_str(&collection);
+        out.push_str("  </COLLECTION>\n  <PLAYLISTS>\n");
+        root.write("ROOT", &ids, 2, &mut out);
+        out.push_str("  </PLAYLISTS>\n</DJ_PLAYLISTS>\n");
+
+        vec![Rendered {
+            file_name: FILE_NAME.to_string(),
+            contents: out,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Entry;
+    use super::*;
+
+    fn document(folder_path: &str, sources: &[&str]) -> Document {
+        Document {
+            name: folder_path.rsplit('/').next().unwrap().to_string(),
+            folder_path: folder_path.to_string(),
+            entries: sources
+                .iter()
+                .map(|s| Entry {
+                    path: s.to_string(),
+                    source: s.to_string(),
+                    duration: Some(61.2),
+                    artist: Some("A & B".to_string()),
+                    title: None,
+                    album: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn nests_playlists_in_folders_and_dedupes_tracks() {
+        let playlists = [
+            document("Weatherall/Sabresonic", &["/m/one.flac", "/m/two two.flac"]),
+            document("Weatherall/Deep/Late", &["/m/one.flac"]),
+            document("Loose", &["/m/three.flac"]),
+        ];
+        let xml = &Rekordbox.render(&playlists)[0].contents;
+
+        assert!(xml.contains("<COLLECTION Entries=\"3\">"));
+        assert!(xml.contains(
+            "<TRACK TrackID=\"2\" Artist=\"A &amp; B\" TotalTime=\"61\" \
+             Location=\"file://localhost/m/two%20two.flac\"/>"
+        ));
+
+        let weatherall = xml.find("<NODE Type=\"0\" Name=\"Weatherall\" Count=\"2\">").unwrap();
+        let deep = xml.find("<NODE Type=\"0\" Name=\"Deep\" Count=\"1\">").unwrap();
+        let late = xml.find("<NODE Name=\"Late\" Type=\"1\" KeyType=\"0\" Entries=\"1\">").unwrap();
+        assert!(weatherall < deep && deep < late);
+        assert!(xml.contains("<NODE Type=\"0\" Name=\"ROOT\" Count=\"2\">"));
+    }
+}
@@ -0,0 +1,61 @@
+use super::{nonempty, percent_encode, xml_escape, Document, PerPlaylist};
+
+/// XSPF, for VLC, Strawberry and friends. Locations are written as URIs
+/// relative to the playlist file.
+pub struct Xspf;
+
+impl PerPlaylist for Xspf {
+    fn extension(&self) -> &'static str {
+        "xspf"
+    }
+
+    fn render_one(&self, playlist: &Document) -> String {
+        let mut out = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+        );
+        out.push_str(&format!("  <title>{}</title>\n", xml_escape(&playlist.name)));
+        out.push_str("  <trackList>\n");
+        for entry in &playlist.entries {
+            out.push_str("    <track>\n");
+            out.push_str(&format!(
+                "      <location>{}</location>\n",
+                xml_escape(&relative_uri(&entry.path))
+            ));
+            for (tag, value) in [
+                ("title", &entry.title),
+                ("creator", &entry.artist),
+                ("album", &entry.album),
+            ] {
+                if let Some(value) = nonempty(value) {
+                    out.push_str(&format!("      <{0}>{1}</{0}>\n", tag, xml_escape(value)));
+                }
+            }
+            if let Some(duration) = entry.duration {
+                let millis = (duration * 1000.0).round() as i64;
+                out.push_str(&format!("      <duration>{}</duration>\n", millis));
+            }
+            out.push_str("    </track>\n");
+        }
+        out.push_str("  </trackList>\n</playlist>\n");
+        out
+    }
+}
+
+/// Turns a playlist path into a relative URI reference. A leading `/` would
+/// make the URI absolute, so it is dropped, as are doubled separators.
+fn relative_uri(path: &str) -> String {
+    let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    percent_encode(&parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_relative_locations() {
+        assert_eq!(relative_uri("/A B/one.flac"), "A%20B/one.flac");
+        assert_eq!(relative_uri("..//A/one.flac"), "../A/one.flac");
+    }
+}
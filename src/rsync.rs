@@ -1,131 +1,363 @@
+use crate::copier::{Compare, Copier, FileError, SyncStats};
 use crate::error::Error;
+use std::collections::HashSet;
 use std::io::Write;
-use std::process::{Output, Stdio};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::OnceCell;
 
-pub struct Rsync {
-    source: String,
-    dest: String,
+/// What GNU rsync prints for every file it changes: the itemized change, the
+/// bytes it sent and the name. openrsync only has its `-v` output.
+const OUT_FORMAT: &str = "--out-format=%i %b %n";
+
+/// The installed rsync, found out once per run.
+static FLAVOUR: OnceCell<Flavour> = OnceCell::const_new();
+
+/// Which rsync is installed: GNU rsync on Linux and Homebrew, or the
+/// openrsync macOS ships since Sequoia.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flavour {
+    Gnu,
+    Openrsync,
 }
 
-/// Tally of what an rsync run did, derived from its verbose output.
-#[derive(Debug, Default, Clone, Copy)]
-pub struct SyncStats {
-    pub copied: usize,
-    pub skipped: usize,
+impl Flavour {
+    async fn detect() -> Flavour {
+        *FLAVOUR
+            .get_or_init(|| async {
+                let output = Command::new("rsync")
+                    .arg("--version")
+                    .stdin(Stdio::null())
+                    .output()
+                    .await;
+                match output {
+                    Ok(output) => Flavour::parse(&String::from_utf8_lossy(&output.stdout)),
+                    Err(_) => Flavour::Gnu,
+                }
+            })
+            .await
+    }
+
+    fn parse(version: &str) -> Flavour {
+        if version.contains("openrsync") {
+            Flavour::Openrsync
+        } else {
+            Flavour::Gnu
+        }
+    }
+
+    /// The options that make this rsync report what it does on stdout.
+    fn report_args(self) -> &'static [&'static str] {
+        match self {
+            Flavour::Gnu => &[OUT_FORMAT],
+            Flavour::Openrsync => &["-v"],
+        }
+    }
+
+    /// Tallies one line of the report into `stats`, returning the file it
+    /// was about when it was copied.
+    fn tally(self, line: &str, stats: &mut SyncStats) -> Option<String> {
+        match self {
+            Flavour::Gnu => match parse_itemized(line)? {
+                Change::Created { name, bytes } => {
+                    stats.created += 1;
+                    stats.bytes += bytes;
+                    Some(name)
+                }
+                Change::Updated { name, bytes } => {
+                    stats.updated += 1;
+                    stats.bytes += bytes;
+                    Some(name)
+                }
+                Change::Deleted => {
+                    stats.deleted += 1;
+                    None
+                }
+            },
+            // openrsync doesn't say whether a file was there before, nor how
+            // much it sent.
+            Flavour::Openrsync => match classify(line)? {
+                LineKind::Copied => {
+                    stats.created += 1;
+                    Some(line.trim_end().to_string())
+                }
+                LineKind::Skipped => {
+                    stats.skipped += 1;
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// Copies with the `rsync` executable, locally or over ssh.
+pub struct Rsync {
+    compare: Compare,
 }
 
 impl Rsync {
-    pub fn new(source: &str, dest: &str) -> Rsync {
-        Rsync {
-            source: source.to_string(),
-            dest: dest.to_string(),
+    pub fn new(compare: Compare) -> Rsync {
+        Rsync { compare }
+    }
+
+    /// The options that make rsync tell files on the destination apart the
+    /// way `compare` asks.
+    fn compare_args(&self) -> &'static [&'static str] {
+        match self.compare {
+            Compare::Existing => &["--ignore-existing"],
+            // rsync's own quick check, which needs the times kept.
+            Compare::Mtime => &["-t"],
+            Compare::Checksum => &["-t", "-c"],
         }
     }
 
-    /// Syncs an explicit list of files from `source` to `dest`, feeding the
-    /// file list to rsync over stdin. When `delete_existing` is set, files on
-    /// the destination that aren't in the list are removed.
+    /// Runs rsync over `files`, which it's handed on stdin, and tallies its
+    /// report. `progress` is called with each copied file and the running
+    /// tally.
     ///
-    /// rsync's per-file chatter is consumed rather than printed; instead a
-    /// single status line is rendered in place with a running copied/skipped
-    /// counter.
-    pub async fn sync_selective(
+    /// rsync exiting with an error fails the run, with whatever it said about
+    /// single files.
+    async fn run(
         &self,
+        extra: &[&str],
+        source: &str,
+        dest: &str,
         files: &[String],
-        delete_existing: bool,
-    ) -> Result<SyncStats, Error> {
+        mut progress: impl FnMut(&str, &SyncStats),
+    ) -> Result<(SyncStats, HashSet<String>), Error> {
+        let flavour = Flavour::detect().await;
         let mut cmd = Command::new("rsync");
-
-        if delete_existing {
-            cmd.args([
-                "--ignore-existing",
-                "-r",
-                "-v",
-                "-p",
-                "--include-from=-",
-                "--exclude=*",
-                "--delete-excluded",
-                &self.source,
-                &self.dest,
-            ]);
-        } else {
-            cmd.args([
-                "--ignore-existing",
-                "-r",
-                "-v",
-                "--files-from=-",
-                &self.source,
-                &self.dest,
-            ]);
-        }
-
-        // Capture stdout to count/summarise; leave stderr inherited so genuine
-        // errors still surface.
+        cmd.args(extra).args(self.compare_args()).args(flavour.report_args());
+        // `-L` sends the files behind the symlinks of a staging folder.
+        cmd.args(["-r", "-L", "--files-from=-", source, dest]);
         cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::inherit());
+        cmd.stderr(Stdio::piped());
         cmd.stdin(Stdio::piped());
 
         let mut child = cmd.spawn()?;
         let mut stdin = child.stdin.take().ok_or(Error::CouldNotGetStdin)?;
         let stdout = child.stdout.take().ok_or(Error::CouldNotGetStdin)?;
+        let stderr = child.stderr.take().ok_or(Error::CouldNotGetStdin)?;
 
-        // Feed the file list on a separate task: rsync streams output while we
-        // write, so doing both inline could deadlock on full pipe buffers.
+        // Feed the file list and drain stderr on separate tasks: rsync
+        // streams output while we write, so doing it all inline could
+        // deadlock on full pipe buffers.
         let filelist = files.join("\n");
         let writer = tokio::spawn(async move {
             let _ = stdin.write_all(filelist.as_bytes()).await;
             // `stdin` drops here, signalling EOF to rsync.
         });
+        let complaints = tokio::spawn(async move {
+            let (mut reader, mut buf) = (BufReader::new(stderr), Vec::new());
+            let mut lines = Vec::new();
+            while let Some(line) = next_line(&mut reader, &mut buf).await? {
+                lines.push(line);
+            }
+            Ok::<_, std::io::Error>(lines)
+        });
 
         let mut stats = SyncStats::default();
-        let mut reader = BufReader::new(stdout);
-        let mut buf = Vec::new();
-        let mut stderr = std::io::stderr();
-        // Read raw bytes rather than `lines()`: openrsync echoes filesystem
-        // paths verbatim, which on a foreign-normalised or otherwise non-UTF-8
-        // volume aren't valid UTF-8. `lines()` would error out on the first
-        // such path and abort the whole sync; decoding lossily lets one odd
-        // filename through as a harmless mangled line instead.
-        loop {
-            buf.clear();
-            if reader.read_until(b'\n', &mut buf).await? == 0 {
-                break;
-            }
-            let line = String::from_utf8_lossy(&buf);
-            match classify(&line) {
-                Some(LineKind::Copied) => stats.copied += 1,
-                Some(LineKind::Skipped) => stats.skipped += 1,
-                None => continue,
+        let mut copied = HashSet::new();
+        let (mut reader, mut buf) = (BufReader::new(stdout), Vec::new());
+        while let Some(line) = next_line(&mut reader, &mut buf).await? {
+            if let Some(name) = flavour.tally(&line, &mut stats) {
+                progress(&name, &stats);
+                copied.insert(name);
             }
-            let _ = write!(stderr, "\r  …{} copied, {} skipped   ", stats.copied, stats.skipped);
-            let _ = stderr.flush();
         }
 
         let _ = writer.await;
+        let complaints = complaints.await.map_err(std::io::Error::other)??;
         let status = child.wait().await?;
 
-        // Replace the in-place line with a final summary.
-        let _ = writeln!(stderr, "\r  {} copied, {} skipped        ", stats.copied, stats.skipped);
-
+        for complaint in &complaints {
+            warn!("{}", complaint);
+            stats.errors.extend(parse_error(complaint));
+        }
         if !status.success() {
-            warn!("rsync exited with {}", status);
+            return Err(failure(dest, status, &complaints, stats.errors));
         }
+        // Files rsync found up to date usually go unmentioned.
+        let done = stats.copied() + stats.skipped + stats.errors.len();
+        stats.skipped += files.len().saturating_sub(done);
+
+        Ok((stats, copied))
+    }
+}
+
+impl Copier for Rsync {
+    fn compare(&self) -> Compare {
+        self.compare
+    }
+
+    /// Syncs an explicit list of files from `source` to `dest`.
+    ///
+    /// rsync's per-file report is consumed rather than printed; instead a
+    /// single status line is rendered in place with a running count.
+    async fn copy(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+        mut copied: impl FnMut(&str),
+    ) -> Result<SyncStats, Error> {
+        let mut stderr = std::io::stderr();
+        let (stats, _) = self
+            .run(&[], source, dest, files, |name, stats| {
+                copied(name);
+                let _ = write!(stderr, "\r  …{} copied   ", stats.copied());
+                let _ = stderr.flush();
+            })
+            .await?;
 
+        // Replace the in-place line with a final summary.
+        let _ = writeln!(stderr, "\r  {}        ", stats);
         Ok(stats)
     }
 
-    /// Recursively syncs `source` to `dest`.
-    pub async fn sync_file(&self) -> Result<Output, Error> {
-        let mut cmd = Command::new("rsync");
-        cmd.args(["-r", "-v", &self.source, &self.dest]);
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+    /// Asks rsync, via a dry run, which of `files` it would copy. Paths are
+    /// returned as rsync prints them.
+    async fn missing(
+        &self,
+        source: &str,
+        dest: &str,
+        files: &[String],
+    ) -> Result<HashSet<String>, Error> {
+        let (_, copied) = self.run(&["--dry-run"], source, dest, files, |_, _| {}).await?;
+        Ok(copied)
+    }
+
+    async fn copy_tree(&self, source: &str, dest: &str) -> Result<(), Error> {
+        let output = Command::new("rsync")
+            .args(["-r", "-v", source, dest])
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        if !output.status.success() {
+            let complaints: Vec<String> = String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .map(String::from)
+                .collect();
+            let failed = complaints.iter().filter_map(|c| parse_error(c)).collect();
+            return Err(failure(dest, output.status, &complaints, failed));
+        }
+        Ok(())
+    }
+}
+
+/// The error for an rsync run to `dest` that exited with `status`, told
+/// apart by what rsync complained about and the code it exited with.
+/// `failed` are the files it named.
+fn failure(dest: &str, status: ExitStatus, complaints: &[String], failed: Vec<FileError>) -> Error {
+    let dest = dest.to_string();
+    let mentioning = |needle: &str| complaints.iter().find(|c| c.contains(needle)).cloned();
+
+    if mentioning("No space left on device").is_some() {
+        return Error::DiskFull { dest, failed };
+    }
+    if let Some(message) = mentioning("Permission denied") {
+        return Error::PermissionDenied {
+            dest,
+            message,
+            failed,
+        };
+    }
+    let message = complaints.join("\n");
+    match (mentioning("Connection refused"), status.code()) {
+        (Some(message), _) => Error::ConnectionRefused { dest, message },
+        // Socket errors, daemon timeouts and ssh failing to connect.
+        (None, Some(10 | 35 | 255)) => Error::ConnectionRefused { dest, message },
+        // Some files failed or vanished while the rest were copied.
+        (None, Some(23 | 24)) => Error::PartialTransfer { dest, failed },
+        (None, _) => Error::Rsync {
+            dest,
+            status: status.to_string(),
+            message,
+        },
+    }
+}
 
-        let child = cmd.spawn()?;
-        Ok(child.wait_with_output().await?)
+/// The next line from `reader`, without its line ending, using `buf` as
+/// scratch space.
+///
+/// Reads raw bytes rather than using `lines()`: rsync echoes filesystem
+/// paths verbatim, which on a foreign-normalised or otherwise non-UTF-8
+/// volume aren't valid UTF-8. `lines()` would error out on the first such
+/// path and abort the whole sync; decoding lossily lets one odd filename
+/// through as a harmless mangled line instead.
+async fn next_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> std::io::Result<Option<String>> {
+    buf.clear();
+    if reader.read_until(b'\n', buf).await? == 0 {
+        return Ok(None);
     }
+    let line = String::from_utf8_lossy(buf);
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// A regular file on a destination, as reported by `rsync --list-only`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// Recursively lists the regular files under `location`, which may be local or
+/// remote. Paths are relative to `location`.
+pub async fn list_files(location: &str) -> Result<Vec<ListedFile>, Error> {
+    let dir = format!("{}/", location.trim_end_matches('/'));
+    let output = Command::new("rsync")
+        .args(["--list-only", "-r", &dir])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(Error::List(
+            location.to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(parse_list_line)
+        .collect())
+}
+
+/// Parses one line of `--list-only` output, e.g.
+/// `-rw-r--r--  4,509,124 2020/05/01 10:00:00 A/one.flac`, keeping only
+/// regular files. GNU rsync groups the size with commas; openrsync doesn't.
+fn parse_list_line(line: &str) -> Option<ListedFile> {
+    if !line.starts_with('-') {
+        return None;
+    }
+
+    // Skip the permission, size, date and time columns; whatever follows is
+    // the path, which may itself contain runs of spaces.
+    let mut rest = line;
+    let mut size = None;
+    for column in 0..4 {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        if column == 1 {
+            size = rest[..end].replace(',', "").parse().ok();
+        }
+        rest = &rest[end..];
+    }
+
+    let path = rest.trim_start().trim_end_matches(['\r', '\n']);
+    if path.is_empty() {
+        return None;
+    }
+
+    Some(ListedFile {
+        path: path.to_string(),
+        size: size?,
+    })
 }
 
 enum LineKind {
@@ -154,19 +386,70 @@ fn classify(line: &str) -> Option<LineKind> {
     Some(LineKind::Copied)
 }
 
+/// A change GNU rsync itemized in [`OUT_FORMAT`].
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    Created { name: String, bytes: u64 },
+    Updated { name: String, bytes: u64 },
+    Deleted,
+}
+
+/// Parses a line of [`OUT_FORMAT`] output, e.g.
+/// `>f+++++++++ 4509124 A/one.flac`, keeping transfers of regular files and
+/// deletions. A new file has nothing but `+` after its update type and kind.
+fn parse_itemized(line: &str) -> Option<Change> {
+    let (item, rest) = line.split_once(' ')?;
+    if item == "*deleting" {
+        return Some(Change::Deleted);
+    }
+    let (bytes, name) = rest.trim_start().split_once(' ')?;
+    let mut chars = item.chars();
+    let (update, kind) = (chars.next()?, chars.next()?);
+    if !matches!(update, '<' | '>') || kind != 'f' {
+        return None;
+    }
+
+    let (name, bytes) = (name.to_string(), bytes.parse().ok()?);
+    if chars.all(|c| c == '+') {
+        Some(Change::Created { name, bytes })
+    } else {
+        Some(Change::Updated { name, bytes })
+    }
+}
+
+/// Picks the file and the reason out of an error rsync printed about a
+/// single file, such as GNU's `rsync: [sender] send_files failed to open
+/// "/a.flac": Permission denied (13)` or openrsync's `openrsync: error:
+/// a.flac: open: Permission denied`. rsync's closing summary isn't one.
+fn parse_error(line: &str) -> Option<FileError> {
+    let (program, rest) = line.split_once(": ")?;
+    if !program.contains("rsync") || program.ends_with(" error") {
+        return None;
+    }
+
+    let (path, message) = match rest.split_once('"') {
+        Some((_, quoted)) => {
+            let (path, after) = quoted.split_once('"')?;
+            let after = after.trim_start_matches(':').trim();
+            (path, after.strip_prefix("failed:").unwrap_or(after).trim())
+        }
+        None => rest.strip_prefix("error: ")?.split_once(": ")?,
+    };
+    Some(FileError {
+        path: path.to_string(),
+        message: message.to_string(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    /// Counts a block of real openrsync `-v` output (as captured from the CLI).
-    fn tally(output: &str) -> SyncStats {
+    /// Counts a block of real `flavour` output (as captured from the CLI).
+    fn tally(flavour: Flavour, output: &str) -> SyncStats {
         let mut stats = SyncStats::default();
         for line in output.lines() {
-            match classify(line) {
-                Some(LineKind::Copied) => stats.copied += 1,
-                Some(LineKind::Skipped) => stats.skipped += 1,
-                None => {}
-            }
+            flavour.tally(line, &mut stats);
         }
         stats
     }
@@ -180,9 +463,48 @@ mod tests {
                       \n\
                       sent 4289 bytes  received 42 bytes  43310000 bytes/sec\n\
                       total size is 8192  speedup is 1.89\n";
-        let stats = tally(output);
-        assert_eq!(stats.copied, 2);
+        let stats = tally(Flavour::Openrsync, output);
+        assert_eq!(stats.copied(), 2);
         assert_eq!(stats.skipped, 1);
+        assert!(matches!(classify("Skip existing 'A/one.flac'"), Some(LineKind::Skipped)));
+    }
+
+    #[test]
+    fn tallies_gnu_itemized_output() {
+        let output = ">f+++++++++ 4509124 A/one.flac\n\
+                      cd+++++++++ 0 B/\n\
+                      >f.st...... 1024 A/two  (live).flac\n\
+                      *deleting   0 A/old.flac\n";
+        let stats = tally(Flavour::Gnu, output);
+        assert_eq!((stats.created, stats.updated, stats.deleted), (1, 1, 1));
+        assert_eq!(stats.bytes, 4509124 + 1024);
+        assert_eq!(
+            parse_itemized(">f.st...... 1024 A/two  (live).flac"),
+            Some(Change::Updated {
+                name: "A/two  (live).flac".into(),
+                bytes: 1024
+            })
+        );
+        assert_eq!(Flavour::parse("openrsync: protocol version 29"), Flavour::Openrsync);
+        assert_eq!(Flavour::parse("rsync  version 3.2.7  protocol version 31"), Flavour::Gnu);
+    }
+
+    #[test]
+    fn parses_per_file_errors() {
+        let gnu = "rsync: [sender] send_files failed to open \"/m/a.flac\": Permission denied (13)";
+        assert_eq!(
+            parse_error(gnu),
+            Some(FileError {
+                path: "/m/a.flac".into(),
+                message: "Permission denied (13)".into()
+            })
+        );
+        let missing = "rsync: link_stat \"/m/b.flac\" failed: No such file or directory (2)";
+        assert_eq!(parse_error(missing).unwrap().message, "No such file or directory (2)");
+        let openrsync = "openrsync: error: A/c.flac: open: Permission denied";
+        assert_eq!(parse_error(openrsync).unwrap().path, "A/c.flac");
+        let summary = "rsync error: some files/attrs were not transferred (code 23)";
+        assert!(parse_error(summary).is_none());
     }
 
     #[test]
@@ -193,6 +515,51 @@ mod tests {
         assert!(classify("   ").is_none());
     }
 
+    #[test]
+    fn parses_list_only_output() {
+        let gnu = "-rw-r--r--      4,509,124 2020/05/01 10:00:00 A/06 - Sentinel  (7'' mix).flac";
+        assert_eq!(
+            parse_list_line(gnu),
+            Some(ListedFile {
+                path: "A/06 - Sentinel  (7'' mix).flac".into(),
+                size: 4509124,
+            })
+        );
+
+        let openrsync = "-rw-r--r--  812 2021/01/02 03:04:05 Playlist.m3u";
+        assert_eq!(parse_list_line(openrsync).map(|f| f.size), Some(812));
+
+        assert!(parse_list_line("drwxr-xr-x          4,096 2020/05/01 10:00:00 A").is_none());
+        assert!(parse_list_line("lrwxrwxrwx             11 2020/05/01 10:00:00 link").is_none());
+    }
+
+    #[test]
+    fn tells_failures_apart() {
+        use std::os::unix::process::ExitStatusExt;
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+        let complaints = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+
+        let full = complaints(&[
+            "rsync: write failed on \"/mnt/usb/A/one.flac\": No space left on device (28)",
+            "rsync error: error in file IO (code 11) at receiver.c(381) [receiver=3.2.7]",
+        ]);
+        let failed: Vec<FileError> = full.iter().filter_map(|c| parse_error(c)).collect();
+        let error = failure("/mnt/usb", exited(11), &full, failed);
+        assert!(matches!(error, Error::DiskFull { .. }));
+        assert_eq!(error.failed_files()[0].path, "/mnt/usb/A/one.flac");
+
+        let refused = complaints(&["ssh: connect to host nas port 22: Connection refused"]);
+        let error = failure("nas:/music", exited(255), &refused, Vec::new());
+        assert!(matches!(error, Error::ConnectionRefused { .. }));
+
+        let vanished = complaints(&["file has vanished: \"/m/a.flac\""]);
+        assert!(matches!(
+            failure("nas:/music", exited(24), &vanished, Vec::new()),
+            Error::PartialTransfer { .. }
+        ));
+        assert!(matches!(failure("x", exited(1), &[], Vec::new()), Error::Rsync { .. }));
+    }
+
     #[test]
     fn paths_with_unusual_names_count_as_copied() {
         // Filenames can contain spaces, quotes and apostrophes.
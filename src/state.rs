@@ -0,0 +1,415 @@
+//! Persistent per-target sync state, stored as one JSON file per target in the
+//! state directory, plus the loudness measurements all targets share.
+
+use crate::copier::Compare;
+use crate::error::Error;
+use crate::loudness::LoudnessCache;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many runs of history are kept per target.
+const MAX_HISTORY: usize = 200;
+
+/// How large the daemon's log may grow before it's trimmed.
+const MAX_LOG_BYTES: u64 = 1 << 20;
+
+/// How many lines of the daemon's log are kept when it's trimmed.
+const MAX_LOG_LINES: usize = 1000;
+
+/// Everything recorded about one target.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TargetState {
+    /// Files known to be on the destination, keyed by full source path.
+    pub files: BTreeMap<String, FileRecord>,
+    /// Past runs, oldest first.
+    pub history: Vec<RunRecord>,
+    /// Digest of the target's playlists and their songs as of the last
+    /// successful sync, see [`Plan::digest`](crate::targets::Plan::digest).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<String>,
+}
+
+/// A source file as it was when last pushed to (or found on) a destination.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub destination: String,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+    /// SHA3-256 of the source contents, recorded for files we pushed
+    /// ourselves to a target that compares by checksum.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// The transcode profile the destination copy was made with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// When the file was last pushed, or first found already present.
+    pub pushed_at: u64,
+}
+
+/// A summary of one sync run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub pushed: usize,
+    pub skipped: usize,
+    pub deleted: usize,
+    pub bytes: u64,
+}
+
+/// The contents of the daemon's status file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    /// The daemon's process id.
+    pub pid: u32,
+    pub updated_at: u64,
+    pub targets: BTreeMap<String, TargetStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetStatus {
+    pub activity: Activity,
+    /// When the target entered that state.
+    pub since: u64,
+    /// When the target last synced successfully while watched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_synced: Option<u64>,
+    /// Why the last sync failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activity {
+    /// The target's device isn't connected.
+    Waiting,
+    Connected,
+    Syncing,
+    Synced,
+    Failed,
+}
+
+/// Size and modification time of a source file, used to tell whether it has
+/// changed since it was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub mtime: u64,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> io::Result<Fingerprint> {
+        let meta = fs::metadata(path)?;
+        Ok(Fingerprint {
+            size: meta.len(),
+            mtime: unix_seconds(meta.modified()?),
+        })
+    }
+}
+
+impl TargetState {
+    /// Whether `source` is recorded, was pushed with `profile` and still has
+    /// the recorded size and modification time.
+    pub fn is_current(&self, source: &str, profile: Option<&str>) -> bool {
+        match (self.files.get(source), Fingerprint::of(Path::new(source))) {
+            (Some(record), Ok(fp)) => {
+                record.profile.as_deref() == profile
+                    && record.size == fp.size
+                    && record.mtime == fp.mtime
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `source` is recorded but what's on the destination no longer
+    /// matches it, by the standard of `compare`: with `Mtime` when its size
+    /// or modification time changed or it was pushed with another profile,
+    /// with `Checksum` also when its contents no longer hash to the recorded
+    /// hash. `Existing` never considers anything changed.
+    pub fn changed(&self, source: &str, profile: Option<&str>, compare: Compare) -> bool {
+        let Some(record) = self.files.get(source) else {
+            return false;
+        };
+        let path = Path::new(source);
+        let Ok(fp) = Fingerprint::of(path) else {
+            return false;
+        };
+        let stale = record.profile.as_deref() != profile
+            || record.size != fp.size
+            || record.mtime != fp.mtime;
+        match compare {
+            Compare::Existing => false,
+            Compare::Mtime => stale,
+            Compare::Checksum => {
+                stale
+                    || record
+                        .hash
+                        .as_ref()
+                        .is_some_and(|hash| hash_file(path).is_ok_and(|h| &h != hash))
+            }
+        }
+    }
+
+    /// Records `source` as present at `destination`, with the hash of its
+    /// contents when `hash` is set, which only `Compare::Checksum` reads.
+    pub fn record(
+        &mut self,
+        source: &str,
+        destination: String,
+        profile: Option<&str>,
+        hash: bool,
+    ) -> io::Result<()> {
+        let path = Path::new(source);
+        let fp = Fingerprint::of(path)?;
+        let hash = if hash { Some(hash_file(path)?) } else { None };
+        self.files.insert(
+            source.to_string(),
+            FileRecord {
+                destination,
+                size: fp.size,
+                mtime: fp.mtime,
+                hash,
+                profile: profile.map(String::from),
+                pushed_at: now(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Forgets whichever file was recorded at `destination`.
+    pub fn forget_destination(&mut self, destination: &str) {
+        self.files.retain(|_, r| r.destination != destination);
+    }
+
+    pub fn push_history(&mut self, run: RunRecord) {
+        self.history.push(run);
+        if self.history.len() > MAX_HISTORY {
+            let excess = self.history.len() - MAX_HISTORY;
+            self.history.drain(..excess);
+        }
+    }
+}
+
+/// The directory holding every target's state file.
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> StateStore {
+        StateStore { dir: dir.into() }
+    }
+
+    fn path(&self, target: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", filenamify::filenamify(target)))
+    }
+
+    /// Where state shared by all targets is kept, out of the way of the
+    /// per-target files.
+    fn loudness_path(&self) -> PathBuf {
+        self.dir.join("shared").join("loudness.json")
+    }
+
+    /// Loads the state for `target`, or an empty state if none was saved yet.
+    pub fn load(&self, target: &str) -> Result<TargetState, Error> {
+        read(&self.path(target))
+    }
+
+    /// Saves the state for `target`, replacing the previous file atomically.
+    pub fn save(&self, target: &str, state: &TargetState) -> Result<(), Error> {
+        write(&self.path(target), state)
+    }
+
+    /// Loads the loudness measurements taken so far.
+    pub fn load_loudness(&self) -> Result<LoudnessCache, Error> {
+        read(&self.loudness_path())
+    }
+
+    pub fn save_loudness(&self, cache: &LoudnessCache) -> Result<(), Error> {
+        write(&self.loudness_path(), cache)
+    }
+
+    /// Where the daemon keeps the latest state of each target it watches.
+    pub fn status_path(&self) -> PathBuf {
+        self.dir.join("shared").join("status.json")
+    }
+
+    /// Where the daemon logs what it does.
+    pub fn log_path(&self) -> PathBuf {
+        self.dir.join("shared").join("daemon.log")
+    }
+
+    pub fn save_status(&self, status: &DaemonStatus) -> Result<(), Error> {
+        write(&self.status_path(), status)
+    }
+
+    /// Appends `line` to the daemon's log. Once the log passes
+    /// `MAX_LOG_BYTES` it's trimmed to its last `MAX_LOG_LINES` lines.
+    pub fn append_log(&self, line: &str) -> Result<(), Error> {
+        let path = self.log_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::File::options().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", line)?;
+        if file.metadata()?.len() <= MAX_LOG_BYTES {
+            return Ok(());
+        }
+
+        // Written aside and renamed into place, so a crash can't lose the log.
+        let log = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = log.lines().collect();
+        let excess = lines.len().saturating_sub(MAX_LOG_LINES);
+        let mut kept = lines[excess..].join("\n");
+        kept.push('\n');
+        let tmp = path.with_extension("log.tmp");
+        fs::write(&tmp, kept)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+fn read<T: DeserializeOwned + Default>(path: &Path) -> Result<T, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Hex-encoded SHA3-256 digest of the file at `path`.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha3_256::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The current time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    unix_seconds(SystemTime::now())
+}
+
+fn unix_seconds(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS` UTC.
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days, after Howard Hinnant's date algorithms.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps_as_utc() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn tells_changed_sources() {
+        let path = std::env::temp_dir().join(format!("shittysync-state-{}", std::process::id()));
+        let source = path.to_str().unwrap();
+        fs::write(&path, "first rip").unwrap();
+        let mut state = TargetState::default();
+        state.record(source, "dest".to_string(), None, true).unwrap();
+        assert!(!state.changed(source, None, Compare::Mtime));
+        assert!(state.changed(source, Some("aac-256k"), Compare::Mtime));
+
+        // Same size and modification time, different contents.
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, "fixed tag").unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        assert!(!state.changed(source, None, Compare::Mtime));
+        assert!(state.changed(source, None, Compare::Checksum));
+
+        fs::write(&path, "a better rip").unwrap();
+        assert!(state.changed(source, None, Compare::Mtime));
+        assert!(!state.changed(source, None, Compare::Existing));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let mut state = TargetState::default();
+        for i in 0..MAX_HISTORY + 5 {
+            state.push_history(RunRecord {
+                started_at: i as u64,
+                ..RunRecord::default()
+            });
+        }
+        assert_eq!(state.history.len(), MAX_HISTORY);
+        assert_eq!(state.history[0].started_at, 5);
+    }
+
+    #[test]
+    fn caps_the_daemon_log() {
+        let dir = std::env::temp_dir().join(format!("shittysync-log-{}", std::process::id()));
+        let store = StateStore::new(&dir);
+        fs::create_dir_all(store.log_path().parent().unwrap()).unwrap();
+        let line = format!("{:<99}\n", "old");
+        let log = line.repeat(MAX_LOG_BYTES as usize / line.len() + 1);
+        fs::write(store.log_path(), log).unwrap();
+        store.append_log("new").unwrap();
+
+        let log = fs::read_to_string(store.log_path()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(log.lines().count(), MAX_LOG_LINES);
+        assert_eq!(log.lines().last(), Some("new"));
+    }
+}
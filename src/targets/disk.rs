@@ -0,0 +1,53 @@
+use super::{CopyConfig, CopyTarget, Plan, SyncTarget, Verification};
+use crate::config::{Config, DiskConfig, Target};
+use crate::state::TargetState;
+use crate::transcode::Transcoder;
+use anyhow::Result;
+use swinsiandb::Database;
+
+/// Syncs playlists to a disk destination, writing playlists into a separate
+/// playlist folder that references the music via `../`.
+pub struct DiskTarget<'a> {
+    copies: CopyTarget<'a>,
+}
+
+impl<'a> DiskTarget<'a> {
+    pub fn new(target: &'a Target, cfg: &'a DiskConfig, transcoder: Option<Transcoder>) -> Self {
+        let copy = CopyConfig {
+            destination: cfg.destination.clone(),
+            playlist_folder: Some(cfg.playlistfolder.clone()),
+            // The playlist folder sits next to the music, so entries step up
+            // one level.
+            playlist_prefix: "../",
+            formats: &cfg.formats,
+            coverfile: cfg.coverfile.as_deref(),
+            filesystem: cfg.filesystem,
+            normalisation: cfg.normalisation,
+            mirror: cfg.mirror,
+            backend: cfg.copier,
+        };
+        DiskTarget {
+            copies: CopyTarget::new(target, copy, transcoder),
+        }
+    }
+}
+
+impl SyncTarget for DiskTarget<'_> {
+    async fn plan(&mut self, db: &Database, cfg: &Config, known: &TargetState) -> Result<Plan> {
+        self.copies.plan(db, cfg, known).await
+    }
+
+    async fn apply(&mut self, cfg: &Config, plan: &Plan, state: &mut TargetState) -> Result<()> {
+        self.copies.apply(cfg, plan, state).await
+    }
+
+    async fn verify(
+        &mut self,
+        db: &Database,
+        cfg: &Config,
+        known: &TargetState,
+        repair: bool,
+    ) -> Result<Verification> {
+        self.copies.verify(db, cfg, known, repair).await
+    }
+}
@@ -0,0 +1,76 @@
+use super::{destination_path, Plan};
+use crate::filesystem::{self, Filesystem, Normalisation};
+use crate::transcode::Transcoder;
+use std::collections::HashMap;
+
+/// Where each of a plan's songs goes on a destination, relative to its root:
+/// the song's path below the basepath, with the profile's extension when
+/// transcoding, in the destination's normalisation form and made acceptable
+/// to its filesystem.
+///
+/// Songs whose sanitised paths would clash are told apart by a hash of their
+/// source path. Sources are handled in sorted order, so the same plan always
+/// lays out the same way.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    filesystem: Filesystem,
+    paths: HashMap<String, String>,
+    /// Whether any song ends up somewhere other than its own path.
+    renamed: bool,
+}
+
+impl Layout {
+    pub fn new(
+        plan: &Plan,
+        basepath: &str,
+        transcoder: Option<&Transcoder>,
+        filesystem: Filesystem,
+        normalisation: Normalisation,
+    ) -> Layout {
+        let mut sources: Vec<&str> = plan.unique_files().into_iter().collect();
+        sources.sort();
+
+        let mut paths = HashMap::new();
+        let mut taken: HashMap<String, &str> = HashMap::new();
+        let mut renamed = false;
+        for source in sources {
+            let original = destination_path(source, basepath, "", transcoder);
+            let mut path = filesystem.sanitise(&normalisation.apply(&original));
+            if let Some(other) = taken.get(&filesystem.collision_key(&path)) {
+                let unique = filesystem::disambiguate(&path, source);
+                warn!("{} and {} would both be {}; using {}", other, source, path, unique);
+                path = unique;
+            }
+            renamed |= path != original;
+            taken.insert(filesystem.collision_key(&path), source);
+            paths.insert(source.to_string(), path);
+        }
+
+        Layout {
+            filesystem,
+            paths,
+            renamed,
+        }
+    }
+
+    /// Where `source`, one of the plan's songs, goes, with `prefix` in front.
+    pub fn path(&self, source: &str, prefix: &str) -> String {
+        let path = self.paths.get(source).map_or(source, String::as_str);
+        format!("{}{}", prefix, path)
+    }
+
+    /// [`path`](Layout::path) of each of `sources`.
+    pub fn paths(&self, sources: &[String], prefix: &str) -> Vec<String> {
+        sources.iter().map(|s| self.path(s, prefix)).collect()
+    }
+
+    /// Whether any song goes somewhere other than its path below the
+    /// basepath, so sources can't be copied straight from the library.
+    pub fn renames(&self) -> bool {
+        self.renamed
+    }
+
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.filesystem.max_file_size()
+    }
+}
@@ -0,0 +1,895 @@
+//! Sync targets: every `[[target]]` in the config is driven through the
+//! [`SyncTarget`] trait, one implementation per `kind`.
+
+mod disk;
+mod layout;
+mod mtp;
+mod plan;
+mod rsync;
+mod verify;
+mod webdav;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use rayon::prelude::*;
+use swinsiandb::{Database, Playlist};
+
+use crate::artwork::{self, Cover};
+use crate::config::{Config, Target, TargetKind};
+use crate::copier::{AnyCopier, Backend, Compare, Copier};
+use crate::destination::{self, Destination};
+use crate::filesystem::{self, Filesystem, Normalisation};
+use crate::playlist::{self, PlaylistFormat};
+use crate::rsync::list_files;
+use crate::state::{self, RunRecord, StateStore, TargetState};
+use crate::transcode::Transcoder;
+
+use layout::Layout;
+use plan::file_size;
+use verify::verify_copies;
+
+pub use disk::DiskTarget;
+pub use mtp::MtpTarget;
+pub use plan::{format_bytes, ActionKind, CopyProgress, Plan};
+pub use rsync::RsyncTarget;
+pub use verify::Verification;
+pub use webdav::WebdavTarget;
+
+/// A destination that playlists can be synced to.
+///
+/// Syncing is split in two: [`plan`](SyncTarget::plan) works out what should
+/// end up on the destination, and [`apply`](SyncTarget::apply) makes it so.
+pub trait SyncTarget {
+    /// Resolves the target's playlist selection into a [`Plan`]. Files that
+    /// `known` records as already pushed, and unchanged since, can be skipped
+    /// without inspecting the destination.
+    async fn plan(&mut self, db: &Database, cfg: &Config, known: &TargetState) -> Result<Plan>;
+
+    /// Pushes a previously computed plan to the destination, recording what
+    /// ended up there in `state`.
+    async fn apply(&mut self, cfg: &Config, plan: &Plan, state: &mut TargetState) -> Result<()>;
+
+    /// Compares the target's songs that `known` records as synced with what's
+    /// on the destination, by content. With `repair`, the missing and
+    /// differing ones are pushed again.
+    async fn verify(
+        &mut self,
+        db: &Database,
+        cfg: &Config,
+        known: &TargetState,
+        repair: bool,
+    ) -> Result<Verification>;
+}
+
+/// How a target should be synced.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncOptions {
+    /// Only print the plan; don't touch the destination.
+    pub dry_run: bool,
+    /// When printing the plan, list every individual action.
+    pub list_files: bool,
+    /// Delete files from MTP devices that no selected playlist wants.
+    pub prune: bool,
+    /// Ignore the recorded state and inspect every destination afresh.
+    pub rescan: bool,
+}
+
+/// The profile MTP targets transcode with unless they name another.
+const MTP_PROFILE: &str = "aac-256k";
+
+/// Plans and applies a single configured target.
+pub async fn sync(db: &Database, cfg: &Config, target: &Target, opts: SyncOptions) -> Result<()> {
+    let sync_target = AnyTarget::new(cfg, target, opts.prune)?;
+    run(sync_target, db, cfg, &target.name, opts).await
+}
+
+/// Checks a single configured target's destination against what the
+/// recorded state says was synced to it.
+pub async fn verify(
+    db: &Database,
+    cfg: &Config,
+    target: &Target,
+    repair: bool,
+) -> Result<Verification> {
+    let known = StateStore::new(cfg.state_dir())
+        .load(&target.name)
+        .with_context(|| format!("loading state for '{}'", target.name))?;
+    let mut sync_target = AnyTarget::new(cfg, target, false)?;
+    sync_target.verify(db, cfg, &known, repair).await
+}
+
+/// Whichever kind of target a `[[target]]` is.
+enum AnyTarget<'a> {
+    Rsync(RsyncTarget<'a>),
+    Disk(DiskTarget<'a>),
+    Webdav(WebdavTarget<'a>),
+    Mtp(MtpTarget<'a>),
+}
+
+impl<'a> AnyTarget<'a> {
+    /// Sets up the configured `target`, with `prune` passed on to MTP targets.
+    fn new(cfg: &Config, target: &'a Target, prune: bool) -> Result<AnyTarget<'a>> {
+        Ok(match &target.kind {
+            TargetKind::Rsync(kind) => {
+                let transcoder = transcoder_for(cfg, target, None, cfg.cache_dir())?;
+                AnyTarget::Rsync(RsyncTarget::new(target, kind, transcoder))
+            }
+            TargetKind::Disk(kind) => {
+                let transcoder = transcoder_for(cfg, target, None, cfg.cache_dir())?;
+                AnyTarget::Disk(DiskTarget::new(target, kind, transcoder))
+            }
+            TargetKind::Webdav(kind) => {
+                let transcoder = transcoder_for(cfg, target, None, cfg.cache_dir())?;
+                AnyTarget::Webdav(WebdavTarget::new(target, kind, transcoder))
+            }
+            TargetKind::Mtp(kind) => {
+                let cache = PathBuf::from(&kind.workspace);
+                let transcoder = transcoder_for(cfg, target, Some(MTP_PROFILE), cache)?
+                    .context("MTP targets need a transcoding profile")?;
+                AnyTarget::Mtp(MtpTarget::new(target, kind, transcoder, prune))
+            }
+        })
+    }
+}
+
+impl SyncTarget for AnyTarget<'_> {
+    async fn plan(&mut self, db: &Database, cfg: &Config, known: &TargetState) -> Result<Plan> {
+        match self {
+            AnyTarget::Rsync(rsync) => rsync.plan(db, cfg, known).await,
+            AnyTarget::Disk(disk) => disk.plan(db, cfg, known).await,
+            AnyTarget::Webdav(webdav) => webdav.plan(db, cfg, known).await,
+            AnyTarget::Mtp(mtp) => mtp.plan(db, cfg, known).await,
+        }
+    }
+
+    async fn apply(&mut self, cfg: &Config, plan: &Plan, state: &mut TargetState) -> Result<()> {
+        match self {
+            AnyTarget::Rsync(rsync) => rsync.apply(cfg, plan, state).await,
+            AnyTarget::Disk(disk) => disk.apply(cfg, plan, state).await,
+            AnyTarget::Webdav(webdav) => webdav.apply(cfg, plan, state).await,
+            AnyTarget::Mtp(mtp) => mtp.apply(cfg, plan, state).await,
+        }
+    }
+
+    async fn verify(
+        &mut self,
+        db: &Database,
+        cfg: &Config,
+        known: &TargetState,
+        repair: bool,
+    ) -> Result<Verification> {
+        match self {
+            AnyTarget::Rsync(rsync) => rsync.verify(db, cfg, known, repair).await,
+            AnyTarget::Disk(disk) => disk.verify(db, cfg, known, repair).await,
+            AnyTarget::Webdav(webdav) => webdav.verify(db, cfg, known, repair).await,
+            AnyTarget::Mtp(mtp) => mtp.verify(db, cfg, known, repair).await,
+        }
+    }
+}
+
+/// Where a target that copies files onto a path puts the songs and their
+/// playlists, and how: what the rsync, disk and WebDAV configs boil down to.
+struct CopyConfig<'a> {
+    /// Where the songs go.
+    destination: String,
+    /// Where the playlist files go, if the target writes any.
+    playlist_folder: Option<String>,
+    /// What leads from the playlist folder to the songs, e.g. `../`.
+    playlist_prefix: &'static str,
+    formats: &'a [PlaylistFormat],
+    coverfile: Option<&'a str>,
+    filesystem: Filesystem,
+    normalisation: Normalisation,
+    mirror: bool,
+    backend: Backend,
+}
+
+/// Syncs playlists by copying their songs onto a path, as described by a
+/// [`CopyConfig`]. The rsync, disk and WebDAV targets are built on it.
+struct CopyTarget<'a> {
+    target: &'a Target,
+    cfg: CopyConfig<'a>,
+    transcoder: Option<Transcoder>,
+    /// Covers found while planning, written out by `apply`.
+    covers: Vec<Cover>,
+    /// Where the planned songs go, worked out by `plan`.
+    layout: Layout,
+    copier: AnyCopier,
+}
+
+impl<'a> CopyTarget<'a> {
+    fn new(target: &'a Target, cfg: CopyConfig<'a>, transcoder: Option<Transcoder>) -> Self {
+        CopyTarget {
+            target,
+            copier: cfg.backend.copier(target.compare),
+            cfg,
+            transcoder,
+            covers: Vec::new(),
+            layout: Layout::default(),
+        }
+    }
+
+    /// The plan's playlists rendered in the configured formats.
+    fn render_playlists(&self, plan: &Plan) -> Vec<playlist::Rendered> {
+        let (layout, prefix) = (&self.layout, self.cfg.playlist_prefix);
+        plan.render_playlists(self.cfg.formats, |source| layout.path(source, prefix))
+    }
+}
+
+impl SyncTarget for CopyTarget<'_> {
+    async fn plan(&mut self, db: &Database, cfg: &Config, known: &TargetState) -> Result<Plan> {
+        let mut plan = Plan::for_target(db, self.target)?;
+        if let Some(transcoder) = self.transcoder.as_mut() {
+            prepare_transcoder(cfg, &mut plan, transcoder)?;
+        }
+        let transcoder = self.transcoder.as_ref();
+        let (filesystem, normalisation) = (self.cfg.filesystem, self.cfg.normalisation);
+        let layout = Layout::new(&plan, &cfg.basepath, transcoder, filesystem, normalisation);
+        let dest = &self.cfg.destination;
+        let copier = &self.copier;
+        plan_rsync(&mut plan, &cfg.basepath, dest, known, transcoder, &layout, copier).await?;
+        if let Some(name) = self.cfg.coverfile {
+            self.covers = plan_covers(&mut plan, dest, name, &layout);
+        }
+        self.layout = layout;
+
+        let folder = self.cfg.playlist_folder.as_deref();
+        if let Some(folder) = folder {
+            let rendered = self.render_playlists(&plan);
+            plan.push_playlists(folder, &rendered);
+        }
+        if self.cfg.mirror {
+            plan_mirror(&mut plan, &self.cfg.destination, folder, &self.layout).await?;
+        }
+
+        Ok(plan)
+    }
+
+    async fn apply(&mut self, cfg: &Config, plan: &Plan, state: &mut TargetState) -> Result<()> {
+        if let Some(transcoder) = self.transcoder.as_mut() {
+            measure_loudness(cfg, plan, transcoder)?;
+        }
+        let transcoder = self.transcoder.as_ref();
+        let (layout, copier) = (&self.layout, &self.copier);
+        let dest = &self.cfg.destination;
+        // Every playlist's songs go in one transfer, rather than one per
+        // playlist, each reported as its last song lands.
+        let mut progress = CopyProgress::new(plan);
+        let copies = progress.files();
+        info!("Syncing {} files for {} playlists", copies.len(), plan.playlists.len());
+        if let Some(transcoder) = transcoder {
+            forget_changed(plan, transcoder)?;
+        }
+        let hash = self.target.compare == Compare::Checksum;
+        let profile = transcoder.map(Transcoder::name);
+        let destination_of = |source: &str| destination::join(dest, &layout.path(source, ""));
+        let copied = |source: &str| {
+            progress.copied(source);
+            record_file(state, source, destination_of(source), profile, hash);
+        };
+        copy_files(&copies, &cfg.basepath, dest, transcoder, layout, copier, copied).await?;
+        progress.finish();
+        apply_covers(&self.target.name, &self.covers, dest, copier).await?;
+
+        let folder = self.cfg.playlist_folder.as_deref();
+        if let Some(folder) = folder {
+            let rendered = self.render_playlists(plan);
+            apply_playlists(&self.target.name, &rendered, folder, copier).await?;
+        }
+
+        if self.cfg.mirror {
+            let mut roots = vec![dest.as_str()];
+            roots.extend(folder.filter(|f| f != dest));
+            apply_mirror(plan, &roots).await?;
+        }
+
+        record_plan(plan, state, ActionKind::Copy, profile, hash, destination_of);
+
+        Ok(())
+    }
+
+    async fn verify(
+        &mut self,
+        db: &Database,
+        cfg: &Config,
+        known: &TargetState,
+        repair: bool,
+    ) -> Result<Verification> {
+        let mut plan = Plan::for_target(db, self.target)?;
+        if let Some(transcoder) = self.transcoder.as_mut() {
+            prepare_transcoder(cfg, &mut plan, transcoder)?;
+        }
+        let transcoder = self.transcoder.as_ref();
+        let (dest, backend) = (&self.cfg.destination, self.cfg.backend);
+        verify_copies(&plan, known, dest, transcoder, backend, repair).await
+    }
+}
+
+/// The transcoder for the target's `profile`, or for `default` when it names
+/// none, caching into `cache`. `None` means songs are pushed as they are.
+fn transcoder_for(
+    cfg: &Config,
+    target: &Target,
+    default: Option<&str>,
+    cache: PathBuf,
+) -> Result<Option<Transcoder>> {
+    let Some(name) = target.profile.as_deref().or(default) else {
+        return Ok(None);
+    };
+    let profile = cfg
+        .profile(name)
+        .with_context(|| format!("unknown transcoding profile '{}'", name))?;
+    let transcoder = Transcoder::new(cache, &cfg.basepath, name, profile);
+    transcoder.check()?;
+    Ok(Some(transcoder))
+}
+
+/// Tells `transcoder` about the plan's songs: their tags in the library and,
+/// when the profile asks for ReplayGain, the loudness the shared loudness
+/// cache knows of. Songs it doesn't know yet are noted in the plan, to be
+/// measured by [`measure_loudness`].
+fn prepare_transcoder(cfg: &Config, plan: &mut Plan, transcoder: &mut Transcoder) -> Result<()> {
+    let tracks = plan.unique_tracks();
+    transcoder.describe(&tracks);
+    if transcoder.replaygain().is_none() {
+        return Ok(());
+    }
+
+    let loudness = StateStore::new(cfg.state_dir())
+        .load_loudness()
+        .context("loading loudness measurements")?;
+    transcoder.adjust(&loudness, &tracks);
+    let unmeasured = tracks
+        .iter()
+        .filter(|t| loudness.get(&t.path).is_none())
+        .map(|t| t.path.clone())
+        .collect();
+    plan.unmeasured = unmeasured;
+    Ok(())
+}
+
+/// Measures the loudness of the plan's songs the shared loudness cache
+/// doesn't know yet, and tells `transcoder` the adjustments.
+fn measure_loudness(cfg: &Config, plan: &Plan, transcoder: &mut Transcoder) -> Result<()> {
+    if plan.unmeasured.is_empty() {
+        return Ok(());
+    }
+
+    let store = StateStore::new(cfg.state_dir());
+    let mut loudness = store
+        .load_loudness()
+        .context("loading loudness measurements")?;
+    transcoder.measure(&mut loudness, &plan.unique_tracks());
+    store
+        .save_loudness(&loudness)
+        .context("saving loudness measurements")
+}
+
+async fn run(
+    mut sync_target: impl SyncTarget,
+    db: &Database,
+    cfg: &Config,
+    name: &str,
+    opts: SyncOptions,
+) -> Result<()> {
+    let store = StateStore::new(cfg.state_dir());
+    let mut state = store
+        .load(name)
+        .with_context(|| format!("loading state for '{}'", name))?;
+    let started_at = state::now();
+
+    let empty = TargetState::default();
+    let known = if opts.rescan { &empty } else { &state };
+    let plan = sync_target.plan(db, cfg, known).await?;
+    if opts.dry_run {
+        plan.print(name, opts.list_files);
+        return Ok(());
+    }
+
+    // Whatever was recorded before a failure is still accurate, so the state
+    // is saved either way.
+    let result = sync_target.apply(cfg, &plan, &mut state).await;
+    if result.is_ok() {
+        state.selection = Some(plan.digest());
+    }
+    state.push_history(RunRecord {
+        started_at,
+        finished_at: state::now(),
+        pushed: plan.actions_of(ActionKind::Copy).count()
+            + plan.actions_of(ActionKind::Upload).count(),
+        skipped: plan.actions_of(ActionKind::Skip).count(),
+        deleted: plan.actions_of(ActionKind::Delete).count(),
+        bytes: plan
+            .actions_of(ActionKind::Copy)
+            .chain(plan.actions_of(ActionKind::Upload))
+            .map(|a| a.bytes)
+            .sum(),
+    });
+    store
+        .save(name, &state)
+        .with_context(|| format!("saving state for '{}'", name))?;
+    result
+}
+
+/// Resolves a target's playlist selection into concrete playlists.
+///
+/// `names` are matched exactly (preserving the historical "first match wins"
+/// behaviour for duplicate names). `patterns` are globs matched against each
+/// playlist's folder path — e.g. `"Weatherall/*"` selects everything directly
+/// inside the "Weatherall" folder, `"Weatherall/**"` recurses. Results are
+/// de-duplicated by playlist id.
+fn resolve_playlists(db: &Database, names: &[String], patterns: &[String]) -> Result<Vec<Playlist>> {
+    let mut seen = HashSet::new();
+    let mut resolved = Vec::new();
+
+    for name in names {
+        let playlist = db
+            .get_playlist(name)
+            .with_context(|| format!("looking up playlist '{}'", name))?;
+        if seen.insert(playlist.playlist_id) {
+            resolved.push(playlist);
+        }
+    }
+
+    if !patterns.is_empty() {
+        let matchers = patterns
+            .iter()
+            .map(|p| Pattern::new(p).with_context(|| format!("invalid playlist pattern '{}'", p)))
+            .collect::<Result<Vec<_>>>()?;
+        // `*` stays within one folder level; `**` crosses folder boundaries.
+        let opts = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        let mut pattern_hit = vec![false; matchers.len()];
+        for entry in db.get_playlists_with_paths()? {
+            let mut matched = false;
+            for (i, matcher) in matchers.iter().enumerate() {
+                if matcher.matches_with(&entry.path, opts) {
+                    pattern_hit[i] = true;
+                    matched = true;
+                }
+            }
+            if matched && seen.insert(entry.playlist.playlist_id) {
+                resolved.push(entry.playlist);
+            }
+        }
+
+        for (i, hit) in pattern_hit.iter().enumerate() {
+            if !hit {
+                warn!("pattern '{}' matched no playlists", patterns[i]);
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Adds a copy or skip action for every song in `plan`, preceded by a
+/// transcode action when there's a `transcoder` and no cached transcode yet.
+/// Songs `known` to be on the destination are skipped outright and songs
+/// that changed since they were pushed are copied again; the `copier` is
+/// asked about the rest.
+async fn plan_rsync(
+    plan: &mut Plan,
+    basepath: &str,
+    destination: &str,
+    known: &TargetState,
+    transcoder: Option<&Transcoder>,
+    layout: &Layout,
+    copier: &impl Copier,
+) -> Result<()> {
+    let mut sources: Vec<String> = plan.unique_files().into_iter().map(String::from).collect();
+    sources.sort();
+
+    let profile = transcoder.map(Transcoder::name);
+    let changed = changed_sources(plan, &sources, known, profile, copier.compare());
+    let (current, sources): (Vec<String>, Vec<String>) = sources
+        .into_iter()
+        .partition(|s| known.is_current(s, profile) && !changed.contains(s));
+    for source in &current {
+        plan.push_source(ActionKind::Skip, source);
+    }
+    if sources.is_empty() {
+        return Ok(());
+    }
+
+    let relative = layout.paths(&sources, "");
+    let mut listing = None;
+    let missing: HashSet<String> = match transcoder {
+        None if !layout.renames() => copier
+            .missing(basepath, destination, &relative)
+            .await
+            .with_context(|| format!("comparing against {}", destination))?,
+        // Transcodes may not exist yet, and renamed songs aren't in the
+        // library under their new names, so there's nothing to compare; look
+        // for them in a listing of the destination instead.
+        _ => {
+            let present: HashSet<String> = list_paths(destination).await?.into_iter().collect();
+            let missing = relative
+                .iter()
+                .map(|r| r.trim_start_matches('/').to_string())
+                .filter(|r| !present.contains(r))
+                .collect();
+            listing = Some(present);
+            missing
+        }
+    };
+
+    // Songs copied before the target normalised names, or by another tool,
+    // can be on the destination under a name that only looks the same.
+    let unmatched: HashSet<String> = missing
+        .iter()
+        .filter(|r| !r.is_ascii())
+        .map(|r| filesystem::normalisation_key(r))
+        .collect();
+    if !unmatched.is_empty() {
+        let listing = match listing {
+            Some(listing) => listing,
+            None => list_paths(destination).await?.into_iter().collect(),
+        };
+        let planned: HashSet<&str> = relative.iter().map(|r| r.trim_start_matches('/')).collect();
+        plan.misnormalised = listing
+            .into_iter()
+            .filter(|f| !f.is_ascii() && !planned.contains(f.as_str()))
+            .filter(|f| unmatched.contains(&filesystem::normalisation_key(f)))
+            .collect();
+        plan.misnormalised.sort();
+        if !plan.misnormalised.is_empty() {
+            warn!(
+                "{} files on {} only differ from the planned names in Unicode normalisation; \
+                 they'll be copied again under the planned names",
+                plan.misnormalised.len(),
+                destination
+            );
+        }
+    }
+
+    for (source, relative) in sources.iter().zip(&relative) {
+        if !missing.contains(relative.trim_start_matches('/')) && !changed.contains(source) {
+            plan.push_source(ActionKind::Skip, source);
+            continue;
+        }
+
+        // A fresh transcode can only be sized by its source, and a changed
+        // song's cached one is made again.
+        let cached = transcoder
+            .filter(|_| !changed.contains(source))
+            .and_then(|t| t.cached(Path::new(source)));
+        let bytes = match &cached {
+            Some(cached) => cached.metadata().map(|m| m.len()).unwrap_or(0),
+            None => file_size(source),
+        };
+        if layout.max_file_size().is_some_and(|max| bytes > max) {
+            warn!("{} is too large for {}, leaving it out", source, destination);
+            continue;
+        }
+        if let (Some(transcoder), None) = (transcoder, cached) {
+            plan.push_source(transcoder.decide(Path::new(source)).into(), source);
+        }
+        plan.push(ActionKind::Copy, source.clone(), bytes);
+    }
+
+    Ok(())
+}
+
+/// Which of `sources` changed since `known` recorded them as pushed, by the
+/// standard of `compare`, noted in the plan. Their cached transcodes may have
+/// been made from the old contents, so planning ignores them and
+/// [`forget_changed`] drops them when the plan is applied.
+fn changed_sources(
+    plan: &mut Plan,
+    sources: &[String],
+    known: &TargetState,
+    profile: Option<&str>,
+    compare: Compare,
+) -> HashSet<String> {
+    plan.changed = sources
+        .par_iter()
+        .filter(|s| known.changed(s, profile, compare))
+        .cloned()
+        .collect();
+    if !plan.changed.is_empty() {
+        info!("{} songs changed since they were last synced", plan.changed.len());
+    }
+    plan.changed.iter().cloned().collect()
+}
+
+/// Drops the cached transcodes of the plan's changed songs, so they're
+/// encoded again from their new contents.
+fn forget_changed(plan: &Plan, transcoder: &Transcoder) -> Result<()> {
+    for source in &plan.changed {
+        transcoder.forget(Path::new(source))?;
+    }
+    Ok(())
+}
+
+/// The paths of the files below `destination`, relative to it.
+async fn list_paths(destination: &str) -> Result<Vec<String>> {
+    let listing = list_files(destination)
+        .await
+        .with_context(|| format!("listing {}", destination))?;
+    Ok(listing.into_iter().map(|f| f.path).collect())
+}
+
+/// Copies `sources` to `destination` with the `copier` in a single transfer,
+/// transcoding them first when there's a `transcoder`, and placing each where
+/// `layout` says. `copied` is called with each source as it lands.
+async fn copy_files(
+    sources: &[String],
+    basepath: &str,
+    destination: &str,
+    transcoder: Option<&Transcoder>,
+    layout: &Layout,
+    copier: &impl Copier,
+    mut copied: impl FnMut(&str),
+) -> Result<()> {
+    if sources.is_empty() {
+        return Ok(());
+    }
+
+    let relative = layout.paths(sources, "");
+    let root = match transcoder {
+        Some(transcoder) => {
+            info!("Preparing {} files as {}", sources.len(), transcoder.name());
+            sources
+                .par_iter()
+                .map(|s| transcoder.transcode(Path::new(s)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let files: Vec<(PathBuf, String)> =
+                sources.iter().map(PathBuf::from).zip(relative.iter().cloned()).collect();
+            format!("{}/", transcoder.stage(&files)?.display())
+        }
+        None if layout.renames() => {
+            format!("{}/", stage_links(destination, sources, &relative)?.display())
+        }
+        None => basepath.to_string(),
+    };
+
+    let by_path: HashMap<&str, &str> = relative
+        .iter()
+        .map(|r| r.trim_start_matches('/'))
+        .zip(sources.iter().map(String::as_str))
+        .collect();
+    copier
+        .copy(&root, destination, &relative, |path| {
+            if let Some(source) = by_path.get(path.trim_start_matches('/')) {
+                copied(source);
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+/// Lays `sources` out in a fresh staging folder as symlinks named after their
+/// `relative` destination paths, to be copied under those names.
+fn stage_links(destination: &str, sources: &[String], relative: &[String]) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!(
+        "shittysync-staging-{}",
+        filenamify::filenamify(destination)
+    ));
+    match std::fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    for (source, relative) in sources.iter().zip(relative) {
+        let link = dir.join(relative.trim_start_matches('/'));
+        if let Some(parent) = link.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::os::unix::fs::symlink(source, &link)?;
+    }
+    Ok(dir)
+}
+
+/// Finds a cover image for the album folder of every song the plan copies,
+/// adding an action for writing each into its folder on `destination` as
+/// `file_name`. Folders without any art are left alone.
+fn plan_covers(
+    plan: &mut Plan,
+    destination: &str,
+    file_name: &str,
+    layout: &Layout,
+) -> Vec<Cover> {
+    let mut albums: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for action in plan.actions_of(ActionKind::Copy) {
+        if let Some(folder) = Path::new(&action.path).parent() {
+            albums.entry(folder.to_path_buf()).or_default().push(action.path.clone());
+        }
+    }
+
+    let mut covers = Vec::new();
+    for (folder, songs) in albums {
+        let paths: Vec<&Path> = songs.iter().map(Path::new).collect();
+        let Some(image) = artwork::find(&folder, &paths) else {
+            continue;
+        };
+        let song = layout.path(&songs[0], "");
+        let relative = Path::new(&song).parent().unwrap_or(Path::new(""));
+        let relative = relative.to_string_lossy().trim_start_matches('/').to_string();
+        let cover = Cover::new(relative, file_name, image);
+        plan.push(ActionKind::Cover, destination::join(destination, &cover.path()), cover.size());
+        covers.push(cover);
+    }
+    covers
+}
+
+/// Writes the planned `covers` to a scratch folder and copies them into
+/// `destination`, replacing older versions.
+async fn apply_covers(
+    target: &str,
+    covers: &[Cover],
+    destination: &str,
+    copier: &impl Copier,
+) -> Result<()> {
+    if covers.is_empty() {
+        return Ok(());
+    }
+    info!("Writing {} cover images to {}", covers.len(), destination);
+    let dir = artwork::write_temp(target, covers)?;
+    copier
+        .copy_tree(&format!("{}/", dir.display()), destination)
+        .await
+        .with_context(|| format!("copying covers to {}", destination))?;
+    Ok(())
+}
+
+/// Adds a delete action for every audio file under `destination` that isn't
+/// one of the plan's songs and, when `playlist_folder` is given, for every
+/// playlist file directly inside it that the plan doesn't write.
+///
+/// The keep set spans all of the target's playlists at once, so a song shared
+/// between two playlists is never deleted because one of them dropped it.
+async fn plan_mirror(
+    plan: &mut Plan,
+    destination: &str,
+    playlist_folder: Option<&str>,
+    layout: &Layout,
+) -> Result<()> {
+    let keep: HashSet<String> = plan
+        .unique_files()
+        .into_iter()
+        .map(|f| layout.path(f, ""))
+        .map(|f| f.trim_start_matches('/').to_string())
+        .collect();
+
+    let listing = list_files(destination)
+        .await
+        .with_context(|| format!("listing {}", destination))?;
+    for file in &listing {
+        if destination::is_audio(&file.path) && !keep.contains(&file.path) {
+            plan.push(ActionKind::Delete, destination::join(destination, &file.path), file.size);
+        }
+    }
+
+    if let Some(folder) = playlist_folder {
+        let current: HashSet<String> = plan
+            .actions_of(ActionKind::Playlist)
+            .filter_map(|a| destination::strip_root(folder, &a.path))
+            .map(String::from)
+            .collect();
+        let listing = if folder == destination {
+            listing
+        } else {
+            list_files(folder)
+                .await
+                .with_context(|| format!("listing {}", folder))?
+        };
+        for file in listing {
+            let top_level = !file.path.contains('/');
+            if top_level && playlist::is_playlist(&file.path) && !current.contains(&file.path) {
+                plan.push(ActionKind::Delete, destination::join(folder, &file.path), file.size);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Carries out the plan's delete actions, each of which lies under one of
+/// `roots`.
+async fn apply_mirror(plan: &Plan, roots: &[&str]) -> Result<()> {
+    for root in roots {
+        let files: Vec<String> = plan
+            .actions_of(ActionKind::Delete)
+            .filter_map(|a| destination::strip_root(root, &a.path))
+            .map(String::from)
+            .collect();
+        if files.is_empty() {
+            continue;
+        }
+
+        info!("Deleting {} files from {}", files.len(), root);
+        Destination::parse(root)
+            .remove(&files)
+            .await
+            .with_context(|| format!("deleting stale files from {}", root))?;
+    }
+    Ok(())
+}
+
+/// Writes the rendered playlist `files` to a scratch folder and copies them
+/// into `folder` in one go.
+async fn apply_playlists(
+    target: &str,
+    files: &[playlist::Rendered],
+    folder: &str,
+    copier: &impl Copier,
+) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    info!("Writing {} playlist files to {}", files.len(), folder);
+    let dir = playlist::write_temp(target, files).await?;
+    copier
+        .copy_tree(&format!("{}/", dir.display()), folder)
+        .await
+        .with_context(|| format!("copying playlists to {}", folder))?;
+    Ok(())
+}
+
+/// Records the outcome of an applied plan in `state`: `pushed` actions not
+/// already recorded as they landed and newly seen skips are recorded, as made
+/// with `profile`, at the destination `destination_of` maps their source to,
+/// and deletions are forgotten. Pushed files are hashed when `hash` is set.
+fn record_plan(
+    plan: &Plan,
+    state: &mut TargetState,
+    pushed: ActionKind,
+    profile: Option<&str>,
+    hash: bool,
+    destination_of: impl Fn(&str) -> String,
+) {
+    for action in &plan.actions {
+        let hash = match action.kind {
+            ActionKind::Delete => {
+                state.forget_destination(&action.path);
+                continue;
+            }
+            _ if state.is_current(&action.path, profile) => continue,
+            kind if kind == pushed => hash,
+            ActionKind::Skip => false,
+            _ => continue,
+        };
+        let destination = destination_of(&action.path);
+        record_file(state, &action.path, destination, profile, hash);
+    }
+}
+
+/// Records `source` in `state` as present at `destination`, so a transfer
+/// that fails part way still keeps what landed, hashing it when `hash` is
+/// set. Sources that can no longer be read are logged and left out.
+fn record_file(
+    state: &mut TargetState,
+    source: &str,
+    destination: String,
+    profile: Option<&str>,
+    hash: bool,
+) {
+    if let Err(e) = state.record(source, destination, profile, hash) {
+        warn!("could not record {}: {}", source, e);
+    }
+}
+
+/// Where `source` ends up: the configured basepath prefix rewritten to
+/// `prefix` and, when transcoding, the profile's extension in place of the
+/// source's.
+fn destination_path(
+    source: &str,
+    basepath: &str,
+    prefix: &str,
+    transcoder: Option<&Transcoder>,
+) -> String {
+    let path = source.replace(basepath, prefix);
+    match transcoder {
+        Some(t) => Path::new(&path)
+            .with_extension(t.extension())
+            .to_string_lossy()
+            .into_owned(),
+        None => path,
+    }
+}
@@ -0,0 +1,251 @@
+use super::plan::format_bytes;
+use super::verify::expected_files;
+use super::{changed_sources, forget_changed, measure_loudness, prepare_transcoder};
+use super::{record_file, record_plan, ActionKind, Plan, SyncTarget, Verification};
+use crate::config::{Config, MtpConfig, Target};
+use crate::copier::Compare;
+use crate::destination;
+use crate::state::{hash_file, TargetState};
+use crate::transcode::Transcoder;
+use crate::watch::{self, Watch};
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use swinsiandb::Database;
+
+/// Transcodes the selected playlists with the target's profile and uploads
+/// any files missing from an MTP device, or changed since they were uploaded.
+/// With `prune`, files on the device that no selected playlist wants any more
+/// are deleted first.
+pub struct MtpTarget<'a> {
+    target: &'a Target,
+    cfg: &'a MtpConfig,
+    prune: bool,
+    transcoder: Transcoder,
+    /// The device, connected while planning and kept for the apply step.
+    watch: Option<Watch>,
+}
+
+impl<'a> MtpTarget<'a> {
+    pub fn new(
+        target: &'a Target,
+        cfg: &'a MtpConfig,
+        transcoder: Transcoder,
+        prune: bool,
+    ) -> Self {
+        MtpTarget {
+            target,
+            cfg,
+            prune,
+            transcoder,
+            watch: None,
+        }
+    }
+}
+
+impl SyncTarget for MtpTarget<'_> {
+    async fn plan(&mut self, db: &Database, cfg: &Config, known: &TargetState) -> Result<Plan> {
+        let mut plan = Plan::for_target(db, self.target)?;
+        prepare_transcoder(cfg, &mut plan, &mut self.transcoder)?;
+
+        info!("WATCH TIME: finding watch");
+        let mut watch = Watch::new(self.cfg.clone()).await?;
+
+        let mut sources: Vec<String> = plan.unique_files().into_iter().map(String::from).collect();
+        sources.sort();
+
+        let profile = Some(self.transcoder.name());
+        let compare = self.target.compare;
+        let changed = changed_sources(&mut plan, &sources, known, profile, compare);
+
+        // Enumerating the device is slow; skip it when the recorded state
+        // already accounts for every song and nothing needs pruning. Changed
+        // songs need it to find the uploads they replace.
+        let stale = !changed.is_empty() || sources.iter().any(|s| !known.is_current(s, profile));
+        if self.prune || stale {
+            info!("Indexing watch");
+            watch.build_index()?;
+        }
+
+        let mut keep = HashSet::new();
+        for source in sources {
+            let src = Path::new(&source);
+            let Ok(relative) = self.transcoder.relative_output(src) else {
+                continue;
+            };
+            keep.insert(watch::file_hash(&relative));
+
+            let present = known.is_current(&source, profile) || watch.exists(&relative);
+            if present && !changed.contains(&source) {
+                plan.push_source(ActionKind::Skip, &source);
+                continue;
+            }
+
+            // Uploads are sized by the cached transcode when there is one; a
+            // fresh transcode's size can only be estimated from the source. A
+            // changed song's cached transcode is made again.
+            match self.transcoder.cached(src).filter(|_| !changed.contains(&source)) {
+                Some(cached) => {
+                    let bytes = cached.metadata().map(|m| m.len()).unwrap_or(0);
+                    plan.push(ActionKind::Upload, source, bytes);
+                }
+                None => {
+                    plan.push_source(self.transcoder.decide(src).into(), &source);
+                    plan.push_source(ActionKind::Upload, &source);
+                }
+            }
+        }
+
+        let unwanted = watch.unwanted(&keep);
+        if self.prune {
+            for file in unwanted {
+                let path = destination::join(&self.cfg.base_folder, &file.name);
+                plan.push(ActionKind::Delete, path, file.size);
+            }
+        } else if !unwanted.is_empty() {
+            let bytes = unwanted.iter().map(|f| f.size).sum();
+            info!(
+                "{} files ({}) on the watch are no longer selected; pass --prune to delete them",
+                unwanted.len(),
+                format_bytes(bytes)
+            );
+        }
+
+        self.watch = Some(watch);
+        Ok(plan)
+    }
+
+    async fn apply(&mut self, cfg: &Config, plan: &Plan, state: &mut TargetState) -> Result<()> {
+        measure_loudness(cfg, plan, &mut self.transcoder)?;
+        let watch = match &mut self.watch {
+            Some(watch) => watch,
+            None => self.watch.insert(Watch::new(self.cfg.clone()).await?),
+        };
+
+        // Prune before uploading so the freed space is available.
+        let mut freed = 0;
+        for action in plan.actions_of(ActionKind::Delete) {
+            let Some(name) = destination::strip_root(&self.cfg.base_folder, &action.path) else {
+                continue;
+            };
+            info!("Deleting: {}", action.path);
+            watch.delete_file(name)?;
+            freed += action.bytes;
+        }
+        if freed > 0 {
+            info!("Freed {} on the watch", format_bytes(freed));
+        }
+
+        let to_upload: Vec<PathBuf> = plan
+            .actions_of(ActionKind::Upload)
+            .map(|a| PathBuf::from(&a.path))
+            .collect();
+
+        info!(
+            "Transcoding {} files, remuxing {}",
+            plan.actions_of(ActionKind::Transcode).count(),
+            plan.actions_of(ActionKind::Remux).count()
+        );
+        let transcoder = &self.transcoder;
+        forget_changed(plan, transcoder)?;
+        let transfers: Vec<(PathBuf, watch::TransferObject)> = to_upload
+            .into_par_iter()
+            .map(|src| Ok((src.clone(), transcode_for_watch(transcoder, src)?)))
+            .collect::<Result<_>>()?;
+
+        let hash = self.target.compare == Compare::Checksum;
+        let profile = Some(transcoder.name());
+        let destination_of = |source: &str| {
+            let relative = transcoder.relative_output(Path::new(source)).unwrap_or_default();
+            let name = format!("{}.{}", watch::file_hash(&relative), transcoder.extension());
+            destination::join(&self.cfg.base_folder, &name)
+        };
+        info!("Uploading {} files", transfers.len());
+        for (source, transfer) in transfers {
+            info!("Syncing file: {:?}", transfer);
+            watch.put_file(transfer)?;
+            let source = source.to_string_lossy();
+            record_file(state, &source, destination_of(&source), profile, hash);
+        }
+
+        record_plan(plan, state, ActionKind::Upload, profile, hash, destination_of);
+
+        Ok(())
+    }
+
+    /// Compares each synced song's upload with its cached transcode, first by
+    /// size and then by reading it back from the device.
+    async fn verify(
+        &mut self,
+        db: &Database,
+        cfg: &Config,
+        known: &TargetState,
+        repair: bool,
+    ) -> Result<Verification> {
+        let mut plan = Plan::for_target(db, self.target)?;
+        prepare_transcoder(cfg, &mut plan, &mut self.transcoder)?;
+        let (expected, unchecked) = expected_files(&plan, known, Some(&self.transcoder));
+        let mut verification = Verification {
+            unchecked,
+            ..Verification::default()
+        };
+
+        let mut watch = Watch::new(self.cfg.clone()).await?;
+        watch.build_index()?;
+        info!("Reading back {} files from the watch", expected.len());
+        let scratch = format!("shittysync-verify-{}", std::process::id());
+        let scratch = std::env::temp_dir().join(scratch);
+        let mut again = Vec::new();
+        for (source, transcoded, recorded) in expected {
+            let relative = self.transcoder.relative_output(Path::new(&source))?;
+            verification.checked += 1;
+            let intact = match watch.file(&relative) {
+                None => {
+                    verification.missing.push(recorded);
+                    false
+                }
+                Some(file) => {
+                    let size = transcoded.metadata()?.len();
+                    let intact = size == file.size && {
+                        watch.read_file(file, &scratch)?;
+                        hash_file(&scratch)? == hash_file(&transcoded)?
+                    };
+                    if !intact {
+                        verification.mismatched.push(recorded);
+                    }
+                    intact
+                }
+            };
+            if !intact {
+                again.push(watch::TransferObject {
+                    transcoded,
+                    destination: relative,
+                });
+            }
+        }
+        let _ = std::fs::remove_file(&scratch);
+
+        if repair {
+            for transfer in again {
+                info!("Syncing file: {:?}", transfer);
+                watch.put_file(transfer)?;
+                verification.repaired += 1;
+            }
+        }
+        Ok(verification)
+    }
+}
+
+/// Transcodes a single source file and builds the corresponding watch transfer.
+fn transcode_for_watch(transcoder: &Transcoder, src: PathBuf) -> Result<watch::TransferObject> {
+    let transcoded = transcoder.transcode(&src)?;
+    let destination = transcoder
+        .relative_output(&src)
+        .with_context(|| format!("computing destination path for {:?}", src))?;
+
+    Ok(watch::TransferObject {
+        transcoded,
+        destination,
+    })
+}
@@ -0,0 +1,369 @@
+use super::resolve_playlists;
+use crate::config::Target;
+use crate::destination;
+use crate::playlist::{self, Document, PlaylistFormat};
+use crate::transcode::{Decision, Tags};
+use anyhow::{Context, Result};
+use sha3::{Digest, Sha3_256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use swinsiandb::{Database, Playlist, Track};
+
+/// What syncing a target involves: each selected playlist with the full source
+/// paths of its songs, and the individual actions needed to bring the
+/// destination up to date.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub playlists: Vec<PlannedPlaylist>,
+    pub actions: Vec<Action>,
+    /// Files on the destination whose names only differ from where a song
+    /// should go in Unicode normalisation, and so don't count as that song.
+    pub misnormalised: Vec<String>,
+    /// Songs that changed since they were last pushed, and are pushed again
+    /// over the stale copy.
+    pub changed: Vec<String>,
+    /// Songs whose loudness isn't known yet, measured for ReplayGain when the
+    /// plan is applied.
+    pub unmeasured: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlannedPlaylist {
+    pub playlist: Playlist,
+    /// The playlist's Swinsian folder path, including its own name.
+    pub folder_path: String,
+    /// The playlist's songs, in order.
+    pub tracks: Vec<Track>,
+    /// The songs' full source paths, in the same order.
+    pub files: Vec<String>,
+}
+
+/// A single step of a plan. `path` is the full source path for file actions
+/// and the destination path for playlist writes and deletions; `bytes` is the
+/// amount of data involved, as far as it can be known up front.
+#[derive(Debug, Clone)]
+pub struct Action {
+    pub kind: ActionKind,
+    pub path: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+    /// Copy a source file to the destination.
+    Copy,
+    /// The file is already on the destination.
+    Skip,
+    /// Encode a source file into the transcode cache.
+    Transcode,
+    /// Rewrap a source whose audio already matches the profile into the
+    /// profile's container.
+    Remux,
+    /// Use a source that already matches the profile as it is.
+    Passthrough,
+    /// Upload a transcoded file to an MTP device.
+    Upload,
+    /// Write a playlist file.
+    Playlist,
+    /// Write an album's cover image next to its songs.
+    Cover,
+    /// Remove a file from the destination.
+    Delete,
+}
+
+impl ActionKind {
+    const ALL: [ActionKind; 9] = [
+        ActionKind::Copy,
+        ActionKind::Skip,
+        ActionKind::Transcode,
+        ActionKind::Remux,
+        ActionKind::Passthrough,
+        ActionKind::Upload,
+        ActionKind::Playlist,
+        ActionKind::Cover,
+        ActionKind::Delete,
+    ];
+}
+
+impl fmt::Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            ActionKind::Copy => "copy",
+            ActionKind::Skip => "skip",
+            ActionKind::Transcode => "transcode",
+            ActionKind::Remux => "remux",
+            ActionKind::Passthrough => "passthrough",
+            ActionKind::Upload => "upload",
+            ActionKind::Playlist => "playlist",
+            ActionKind::Cover => "cover",
+            ActionKind::Delete => "delete",
+        };
+        f.pad(label)
+    }
+}
+
+impl From<Decision> for ActionKind {
+    fn from(decision: Decision) -> ActionKind {
+        match decision {
+            Decision::Encode => ActionKind::Transcode,
+            Decision::Remux => ActionKind::Remux,
+            Decision::Passthrough => ActionKind::Passthrough,
+        }
+    }
+}
+
+impl PlannedPlaylist {
+    /// This playlist as a document ready to be written out, with each song's
+    /// path mapped through `path_of`.
+    pub fn document(&self, path_of: &impl Fn(&str) -> String) -> Document {
+        Document {
+            name: self.playlist.name.clone(),
+            folder_path: self.folder_path.clone(),
+            entries: self
+                .tracks
+                .iter()
+                .map(|t| playlist::Entry::new(t, path_of(&t.path)))
+                .collect(),
+        }
+    }
+}
+
+impl Plan {
+    /// Resolves `target`'s playlist selection and reads the songs of each
+    /// resolved playlist. The returned plan has no actions yet.
+    pub fn for_target(db: &Database, target: &Target) -> Result<Plan> {
+        let folder_paths: HashMap<_, _> = db
+            .get_playlists_with_paths()?
+            .into_iter()
+            .map(|e| (e.playlist.playlist_id, e.path))
+            .collect();
+
+        let playlists = resolve_playlists(db, &target.playlists, &target.patterns)?
+            .into_iter()
+            .map(|playlist| {
+                info!("Collecting: {}", playlist.name);
+                let tracks = db
+                    .get_playlist_songs(&playlist)
+                    .with_context(|| format!("reading songs for playlist '{}'", playlist.name))?;
+                let files = tracks.iter().map(|t| t.path.clone()).collect();
+                let folder_path = folder_paths
+                    .get(&playlist.playlist_id)
+                    .cloned()
+                    .unwrap_or_else(|| playlist.name.clone());
+                Ok(PlannedPlaylist {
+                    playlist,
+                    folder_path,
+                    tracks,
+                    files,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Plan {
+            playlists,
+            ..Plan::default()
+        })
+    }
+
+    /// A digest of the selected playlists and their songs, with the tags the
+    /// library has for them, that changes whenever any of those do.
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha3_256::new();
+        for planned in &self.playlists {
+            hasher.update(format!("{}\0", planned.folder_path));
+            for track in &planned.tracks {
+                hasher.update(format!("{}\0{:?}\0", track.path, Tags::from_track(track)));
+            }
+        }
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The de-duplicated set of source paths across every planned playlist.
+    pub fn unique_files(&self) -> HashSet<&str> {
+        self.playlists
+            .iter()
+            .flat_map(|p| p.files.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Every planned playlist's tracks, each once, in playlist order.
+    pub fn unique_tracks(&self) -> Vec<&Track> {
+        let mut seen = HashSet::new();
+        self.playlists
+            .iter()
+            .flat_map(|p| &p.tracks)
+            .filter(|t| seen.insert(t.path.as_str()))
+            .collect()
+    }
+
+    pub fn push(&mut self, kind: ActionKind, path: impl Into<String>, bytes: u64) {
+        self.actions.push(Action {
+            kind,
+            path: path.into(),
+            bytes,
+        });
+    }
+
+    /// Adds a [`Copy`](ActionKind::Copy) or [`Skip`](ActionKind::Skip) action
+    /// for a source file, sized from the file on disk.
+    pub fn push_source(&mut self, kind: ActionKind, path: &str) {
+        self.push(kind, path, file_size(path));
+    }
+
+    /// Renders every planned playlist in each of `formats`, with each song's
+    /// path mapped through `path_of`.
+    pub fn render_playlists(
+        &self,
+        formats: &[PlaylistFormat],
+        path_of: impl Fn(&str) -> String,
+    ) -> Vec<playlist::Rendered> {
+        let documents: Vec<Document> = self
+            .playlists
+            .iter()
+            .map(|p| p.document(&path_of))
+            .collect();
+        playlist::render_all(formats, &documents)
+    }
+
+    /// Adds a [`Playlist`](ActionKind::Playlist) action for each of `files`,
+    /// written into `folder`.
+    pub fn push_playlists(&mut self, folder: &str, files: &[playlist::Rendered]) {
+        for file in files {
+            let path = destination::join(folder, &file.file_name);
+            self.push(ActionKind::Playlist, path, file.contents.len() as u64);
+        }
+    }
+
+    /// The actions of the given kind.
+    pub fn actions_of(&self, kind: ActionKind) -> impl Iterator<Item = &Action> {
+        self.actions.iter().filter(move |a| a.kind == kind)
+    }
+
+    /// The paths of the actions of the given kind.
+    pub fn paths_of(&self, kind: ActionKind) -> HashSet<&str> {
+        self.actions_of(kind).map(|a| a.path.as_str()).collect()
+    }
+
+    /// Prints per-kind counts and byte totals for the target named `name`,
+    /// followed by every individual action when `list_files` is set.
+    pub fn print(&self, name: &str, list_files: bool) {
+        println!("{}: {} playlists", name, self.playlists.len());
+        for kind in ActionKind::ALL {
+            let (count, bytes) = self
+                .actions_of(kind)
+                .fold((0, 0), |(n, b), a| (n + 1, b + a.bytes));
+            if count > 0 {
+                println!("  {:<11} {:>6} files {:>10}", kind, count, format_bytes(bytes));
+            }
+        }
+        if !self.misnormalised.is_empty() {
+            println!(
+                "  {} files on the destination differ from the planned names only in \
+                 Unicode normalisation",
+                self.misnormalised.len()
+            );
+        }
+        if !self.changed.is_empty() {
+            println!(
+                "  {} songs changed since they were last synced and are sent again",
+                self.changed.len()
+            );
+        }
+        if !self.unmeasured.is_empty() {
+            println!(
+                "  {} songs would have their loudness measured to compute ReplayGain",
+                self.unmeasured.len()
+            );
+        }
+
+        if list_files {
+            for action in &self.actions {
+                println!("  {:<11} {}", action.kind, action.path);
+            }
+            for path in &self.misnormalised {
+                println!("  {:<11} {}", "misnamed", path);
+            }
+        }
+    }
+}
+
+/// Follows a single transfer of all of a plan's copies, reporting each
+/// playlist as soon as its songs are on the destination.
+pub struct CopyProgress<'a> {
+    /// Each playlist's name with the copies it's still waiting for.
+    pending: Vec<(&'a str, HashSet<&'a str>)>,
+}
+
+impl<'a> CopyProgress<'a> {
+    pub fn new(plan: &'a Plan) -> CopyProgress<'a> {
+        let to_copy = plan.paths_of(ActionKind::Copy);
+        let pending = plan
+            .playlists
+            .iter()
+            .map(|p| {
+                let files = p.files.iter().map(String::as_str);
+                (p.playlist.name.as_str(), files.filter(|f| to_copy.contains(f)).collect())
+            })
+            .collect();
+        CopyProgress { pending }
+    }
+
+    /// Every playlist's copies, each once, in order.
+    pub fn files(&self) -> Vec<String> {
+        let files: BTreeSet<&str> = self.pending.iter().flat_map(|(_, f)| f.clone()).collect();
+        files.into_iter().map(String::from).collect()
+    }
+
+    /// Notes that `source` has been copied.
+    pub fn copied(&mut self, source: &str) {
+        for (name, waiting) in &mut self.pending {
+            if waiting.remove(source) && waiting.is_empty() {
+                info!("Synced: {}", name);
+            }
+        }
+    }
+
+    /// Reports the playlists still waiting once the transfer is over, whose
+    /// remaining songs turned out to be on the destination already.
+    pub fn finish(self) {
+        for (name, waiting) in self.pending {
+            if !waiting.is_empty() {
+                info!("Synced: {}", name);
+            }
+        }
+    }
+}
+
+/// Size of the file at `path`, or zero if it can't be read.
+pub fn file_size(path: &str) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Formats a byte count with a binary unit suffix, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_bytes_with_binary_units() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
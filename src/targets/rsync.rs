@@ -0,0 +1,63 @@
+use super::{CopyConfig, CopyTarget, Plan, SyncTarget, Verification};
+use crate::config::{Config, RsyncConfig, Target};
+use crate::state::TargetState;
+use crate::transcode::Transcoder;
+use anyhow::Result;
+use bluos_api_rs::{BluOS, Discovery};
+use swinsiandb::Database;
+
+/// Syncs playlists to a single rsync destination with the playlist files
+/// next to the music, optionally re-indexing a BluOS player afterwards.
+pub struct RsyncTarget<'a> {
+    cfg: &'a RsyncConfig,
+    copies: CopyTarget<'a>,
+}
+
+impl<'a> RsyncTarget<'a> {
+    pub fn new(target: &'a Target, cfg: &'a RsyncConfig, transcoder: Option<Transcoder>) -> Self {
+        let copy = CopyConfig {
+            destination: cfg.destination.clone(),
+            playlist_folder: Some(cfg.destination.clone()),
+            playlist_prefix: "",
+            formats: &cfg.formats,
+            coverfile: cfg.coverfile.as_deref(),
+            filesystem: cfg.filesystem,
+            normalisation: cfg.normalisation,
+            mirror: cfg.mirror,
+            backend: cfg.copier,
+        };
+        RsyncTarget {
+            cfg,
+            copies: CopyTarget::new(target, copy, transcoder),
+        }
+    }
+}
+
+impl SyncTarget for RsyncTarget<'_> {
+    async fn plan(&mut self, db: &Database, cfg: &Config, known: &TargetState) -> Result<Plan> {
+        self.copies.plan(db, cfg, known).await
+    }
+
+    async fn apply(&mut self, cfg: &Config, plan: &Plan, state: &mut TargetState) -> Result<()> {
+        self.copies.apply(cfg, plan, state).await?;
+
+        if self.cfg.bluos {
+            info!("Re-indexing the BluOS library");
+            let device = Discovery::discover_one().await?;
+            info!("Found BluOS device on: {}", device.hostname);
+            BluOS::new_from_discovered(device)?.update_library().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn verify(
+        &mut self,
+        db: &Database,
+        cfg: &Config,
+        known: &TargetState,
+        repair: bool,
+    ) -> Result<Verification> {
+        self.copies.verify(db, cfg, known, repair).await
+    }
+}
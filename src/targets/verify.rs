@@ -0,0 +1,134 @@
+use super::{list_paths, stage_links, Plan};
+use crate::copier::{Backend, Compare, Copier};
+use crate::destination;
+use crate::state::TargetState;
+use crate::transcode::Transcoder;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// What checking a destination against the files synced to it found.
+#[derive(Debug, Clone, Default)]
+pub struct Verification {
+    /// Files compared with what was pushed.
+    pub checked: usize,
+    /// Files that should be on the destination but aren't.
+    pub missing: Vec<String>,
+    /// Files whose contents differ from what was pushed.
+    pub mismatched: Vec<String>,
+    /// Songs that changed since they were synced, or whose transcode is no
+    /// longer cached, so there's nothing to check them against.
+    pub unchecked: usize,
+    /// Missing and mismatched files pushed again.
+    pub repaired: usize,
+}
+
+impl Verification {
+    /// Whether every file checked was on the destination as pushed.
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// The plan's songs that `known` records as synced, each with the file its
+/// destination copy should match, the source itself or its cached transcode,
+/// and where that copy was recorded. Also returns how many recorded songs
+/// have nothing to match.
+pub(super) fn expected_files(
+    plan: &Plan,
+    known: &TargetState,
+    transcoder: Option<&Transcoder>,
+) -> (Vec<(String, PathBuf, String)>, usize) {
+    let profile = transcoder.map(Transcoder::name);
+    let mut sources: Vec<&str> = plan
+        .unique_files()
+        .into_iter()
+        .filter(|s| known.files.contains_key(*s))
+        .collect();
+    sources.sort();
+
+    let mut expected = Vec::new();
+    let mut unchecked = 0;
+    for source in sources {
+        let file = match transcoder {
+            _ if !known.is_current(source, profile) => None,
+            Some(transcoder) => transcoder.cached(Path::new(source)),
+            None => Some(PathBuf::from(source)),
+        };
+        match file {
+            Some(file) => {
+                let destination = known.files[source].destination.clone();
+                expected.push((source.to_string(), file, destination));
+            }
+            None => unchecked += 1,
+        }
+    }
+    (expected, unchecked)
+}
+
+/// Compares the songs synced below `root` with the files they were made from
+/// by checksum, using the `backend`, and with `repair` copies the missing and
+/// differing ones again.
+pub(super) async fn verify_copies(
+    plan: &Plan,
+    known: &TargetState,
+    root: &str,
+    transcoder: Option<&Transcoder>,
+    backend: Backend,
+    repair: bool,
+) -> Result<Verification> {
+    let (expected, mut unchecked) = expected_files(plan, known, transcoder);
+    let mut files = Vec::new();
+    let mut relative = Vec::new();
+    for (_, file, recorded) in expected {
+        // Recorded before the target's destination moved.
+        let Some(path) = destination::strip_root(root, &recorded) else {
+            unchecked += 1;
+            continue;
+        };
+        files.push(file.to_string_lossy().into_owned());
+        relative.push(path.to_string());
+    }
+
+    let mut verification = Verification {
+        checked: files.len(),
+        unchecked,
+        ..Verification::default()
+    };
+    if files.is_empty() {
+        return Ok(verification);
+    }
+
+    info!("Comparing {} files against {}", files.len(), root);
+    let staged = format!("{}/", stage_links(root, &files, &relative)?.display());
+    let copier = backend.copier(Compare::Checksum);
+    let differing = copier
+        .missing(&staged, root, &relative)
+        .await
+        .with_context(|| format!("comparing against {}", root))?;
+    if differing.is_empty() {
+        return Ok(verification);
+    }
+
+    let present: HashSet<String> = list_paths(root).await?.into_iter().collect();
+    let mut again: Vec<String> = differing.into_iter().collect();
+    again.sort();
+    for path in &again {
+        let full = destination::join(root, path);
+        if present.contains(path) {
+            verification.mismatched.push(full);
+        } else {
+            verification.missing.push(full);
+        }
+    }
+
+    if repair {
+        info!("Copying {} files to {} again", again.len(), root);
+        let stats = copier
+            .copy(&staged, root, &again, |_| {})
+            .await
+            .with_context(|| format!("repairing {}", root))?;
+        verification.repaired = stats.copied();
+    }
+    Ok(verification)
+}
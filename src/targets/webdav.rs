@@ -0,0 +1,75 @@
+use super::{CopyConfig, CopyTarget, Plan, SyncTarget, Verification};
+use crate::config::{Config, Target, WebdavConfig};
+use crate::evermusic::Evermusic;
+use crate::state::TargetState;
+use crate::transcode::Transcoder;
+use anyhow::Result;
+use swinsiandb::Database;
+
+/// Discovers the phone over mDNS, mounts its Evermusic WebDAV share and syncs
+/// the union of the selected playlists' songs to it, with the playlist files
+/// next to the music.
+pub struct WebdavTarget<'a> {
+    cfg: &'a WebdavConfig,
+    copies: CopyTarget<'a>,
+    /// The share, mounted while planning and kept for the apply step.
+    evermusic: Option<Evermusic<'a>>,
+}
+
+impl<'a> WebdavTarget<'a> {
+    pub fn new(target: &'a Target, cfg: &'a WebdavConfig, transcoder: Option<Transcoder>) -> Self {
+        let destination = format!("{}/", cfg.mountpath);
+        let copy = CopyConfig {
+            playlist_folder: Some(destination.clone()),
+            destination,
+            playlist_prefix: "",
+            formats: &cfg.formats,
+            coverfile: None,
+            filesystem: cfg.filesystem,
+            normalisation: cfg.normalisation,
+            mirror: cfg.mirror,
+            backend: cfg.copier,
+        };
+        WebdavTarget {
+            cfg,
+            copies: CopyTarget::new(target, copy, transcoder),
+            evermusic: None,
+        }
+    }
+
+    async fn mount(&mut self) -> Result<()> {
+        if self.evermusic.is_none() {
+            info!("Discovering Evermusic");
+            let evermusic = Evermusic::new(&self.cfg.servicename, &self.cfg.mountpath, None).await?;
+            info!(
+                "Found Evermusic WebDAV at {}:{}",
+                evermusic.phone.hostname, evermusic.phone.port
+            );
+            self.evermusic = Some(evermusic);
+        }
+        Ok(())
+    }
+}
+
+impl SyncTarget for WebdavTarget<'_> {
+    async fn plan(&mut self, db: &Database, cfg: &Config, known: &TargetState) -> Result<Plan> {
+        self.mount().await?;
+        self.copies.plan(db, cfg, known).await
+    }
+
+    async fn apply(&mut self, cfg: &Config, plan: &Plan, state: &mut TargetState) -> Result<()> {
+        self.mount().await?;
+        self.copies.apply(cfg, plan, state).await
+    }
+
+    async fn verify(
+        &mut self,
+        db: &Database,
+        cfg: &Config,
+        known: &TargetState,
+        repair: bool,
+    ) -> Result<Verification> {
+        self.mount().await?;
+        self.copies.verify(db, cfg, known, repair).await
+    }
+}
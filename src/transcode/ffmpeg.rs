@@ -0,0 +1,309 @@
+use super::tags::{self, Tags};
+use super::{Art, Codec, Encoder, Probe, Profile, Song};
+use crate::error::Error;
+use crate::loudness::{self, Gain, Loudness};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Shells out to `ffmpeg` and `ffprobe`, which handle every codec.
+pub struct Ffmpeg;
+
+/// Whether `ffmpeg` can be run; checked once per process.
+pub fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        Command::new("ffmpeg")
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success())
+    })
+}
+
+impl Encoder for Ffmpeg {
+    fn name(&self) -> &'static str {
+        "ffmpeg"
+    }
+
+    fn supports(&self, _profile: &Profile) -> bool {
+        true
+    }
+
+    fn probe(&self, file: &Path) -> Result<Probe, Error> {
+        let output = Command::new("ffprobe")
+            .args(["-v", "error", "-select_streams", "a:0", "-show_entries"])
+            .arg(concat!(
+                "stream=codec_name,bit_rate,sample_rate,channels,bits_per_raw_sample",
+                ":format=bit_rate"
+            ))
+            .args(["-of", "default=noprint_wrappers=1"])
+            .arg(file)
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::FFprobe(file.to_string_lossy().into_owned()));
+        }
+        Ok(parse_probe(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn analyse(&self, file: &Path) -> Result<Loudness, Error> {
+        let result = Command::new("ffmpeg")
+            .args(["-nostats", "-hide_banner", "-i"])
+            .arg(file)
+            .args(["-map", "0:a:0", "-filter:a", "ebur128=peak=true", "-f", "null", "-"])
+            .stdin(Stdio::null())
+            .output()?;
+
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        match loudness::parse_ebur128(&stderr) {
+            Some(loudness) if result.status.success() => Ok(loudness),
+            _ => Err(Error::Analyse(
+                file.to_string_lossy().into_owned(),
+                last_line(&stderr).unwrap_or_else(|| result.status.to_string()),
+            )),
+        }
+    }
+
+    fn encode(
+        &self,
+        file: &Path,
+        output: &Path,
+        profile: &Profile,
+        remux: bool,
+        song: &Song,
+    ) -> Result<(), Error> {
+        let codec_args = if remux {
+            vec!["-c:a".to_string(), "copy".to_string()]
+        } else {
+            args(profile)
+        };
+        let result = Command::new("ffmpeg")
+            .args(["-v", "error", "-y", "-i"])
+            .arg(file)
+            .args(codec_args)
+            .args(["-map_metadata", "0", "-map_metadata", "0:s:0"])
+            .args(art_args(profile))
+            .args(song.gain.map(|g| gain_args(profile, &g, remux)).unwrap_or_default())
+            .args(song.tags.as_ref().map(tag_args).unwrap_or_default())
+            .arg(output)
+            .stdin(Stdio::null())
+            .output()?;
+
+        if result.status.success() {
+            let rating = song.tags.as_ref().and_then(Tags::popm_rating);
+            if let (Codec::Mp3, Some(rating)) = (profile.codec, rating) {
+                tags::write_popm(output, rating)?;
+            }
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let message = last_line(&stderr).unwrap_or_else(|| result.status.to_string());
+        Err(Error::FFmpeg(file.to_string_lossy().into_owned(), message))
+    }
+}
+
+/// The last non-empty line of ffmpeg's output, which says what went wrong.
+fn last_line(stderr: &str) -> Option<String> {
+    stderr
+        .lines()
+        .rev()
+        .find(|l| !l.trim().is_empty())
+        .map(|l| l.trim().to_string())
+}
+
+/// Parses ffprobe's `key=value` lines. The stream's bitrate comes before the
+/// container's, which stands in when the stream doesn't report one.
+fn parse_probe(output: &str) -> Probe {
+    let mut probe = Probe::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let number = value.parse::<u32>().ok().filter(|n| *n > 0);
+        match key {
+            "codec_name" => probe.codec = value.to_string(),
+            "bit_rate" if probe.bitrate.is_none() => probe.bitrate = number.map(|b| b / 1000),
+            "sample_rate" => probe.samplerate = number,
+            "channels" => probe.channels = number,
+            "bits_per_raw_sample" => probe.bitdepth = number,
+            _ => {}
+        }
+    }
+    probe
+}
+
+/// The ffmpeg output options for `profile`.
+fn args(profile: &Profile) -> Vec<String> {
+    let mut args = vec!["-c:a".to_string(), encoder(profile).to_string()];
+    if let Some(bitrate) = profile.bitrate {
+        args.extend(["-b:a".to_string(), format!("{}k", bitrate)]);
+    }
+    if let Some(quality) = profile.quality {
+        args.extend(["-q:a".to_string(), quality.to_string()]);
+    }
+    if let Some(rate) = profile.samplerate {
+        args.extend(["-ar".to_string(), rate.to_string()]);
+    }
+    if let Some(fmt) = profile.bitdepth.and_then(|b| sample_format(profile.codec, b)) {
+        args.extend(["-sample_fmt".to_string(), fmt.to_string()]);
+    }
+    if let Some(channels) = profile.channels {
+        args.extend(["-ac".to_string(), channels.to_string()]);
+    }
+    args
+}
+
+/// The ffmpeg output options for the source's cover art, which ffmpeg sees
+/// as a video stream.
+fn art_args(profile: &Profile) -> Vec<String> {
+    let mut args: Vec<String> = match profile.art {
+        _ if !profile.codec.embeds_art() => return vec!["-vn".to_string()],
+        Art::Strip => return vec!["-vn".to_string()],
+        Art::Keep => ["-c:v", "copy"].map(String::from).to_vec(),
+        Art::Resize(size) => vec![
+            "-c:v".to_string(),
+            "mjpeg".to_string(),
+            "-filter:v".to_string(),
+            format!(
+                "scale='min({0},iw)':'min({0},ih)':force_original_aspect_ratio=decrease",
+                size
+            ),
+        ],
+    };
+    // Only the first audio stream and the first picture, if there is one.
+    let maps = ["-map", "0:a:0", "-map", "0:v:0?", "-disposition:v", "attached_pic"];
+    args.splice(0..0, maps.map(String::from));
+    args
+}
+
+/// `-metadata` options that write the library's `tags` over the ones copied
+/// from the source. The rating goes in as `FMPS_RATING`; MP3s get a POPM
+/// frame as well, after encoding.
+fn tag_args(tags: &Tags) -> Vec<String> {
+    let fields = tags.fields.iter().map(|(key, value)| (*key, value.clone()));
+    let rating = tags.fmps_rating().map(|r| ("FMPS_RATING", r));
+    fields
+        .chain(rating)
+        .flat_map(|(key, value)| ["-metadata".to_string(), format!("{}={}", key, value)])
+        .collect()
+}
+
+/// The ffmpeg output options that carry out `gain`: a volume filter when the
+/// profile applies it, ReplayGain tags otherwise. Applying it clears any tags
+/// copied from the source, which would no longer be right.
+fn gain_args(profile: &Profile, gain: &Gain, remux: bool) -> Vec<String> {
+    let Some(mode) = profile.replaygain else {
+        return Vec::new();
+    };
+    let mut args = Vec::new();
+    if profile.applygain && !remux {
+        args.extend(["-filter:a".to_string(), format!("volume={:.2}dB", gain.db(mode))]);
+        for (tag, _) in gain.tags() {
+            args.extend(["-metadata".to_string(), format!("{}=", tag)]);
+        }
+    } else {
+        for (tag, value) in gain.tags() {
+            args.extend(["-metadata".to_string(), format!("{}={}", tag, value)]);
+        }
+    }
+    args
+}
+
+fn encoder(profile: &Profile) -> &'static str {
+    match profile.codec {
+        Codec::Aac => "aac",
+        Codec::Alac => "alac",
+        Codec::Flac => "flac",
+        Codec::Mp3 => "libmp3lame",
+        Codec::Opus => "libopus",
+        Codec::Vorbis => "libvorbis",
+        Codec::Wav => match profile.bitdepth {
+            Some(bits) if bits > 24 => "pcm_s32le",
+            Some(bits) if bits > 16 => "pcm_s24le",
+            _ => "pcm_s16le",
+        },
+    }
+}
+
+/// The ffmpeg sample format for `bits` per sample, for lossless codecs that
+/// take one.
+fn sample_format(codec: Codec, bits: u32) -> Option<&'static str> {
+    let wide = bits > 16;
+    match codec {
+        Codec::Flac => Some(if wide { "s32" } else { "s16" }),
+        Codec::Alac => Some(if wide { "s32p" } else { "s16p" }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loudness::GainMode;
+
+    #[test]
+    fn builds_args_for_profiles() {
+        let watch = Profile::builtin("aac-256k").unwrap();
+        assert_eq!(args(&watch), ["-c:a", "aac", "-b:a", "256k", "-ar", "44100"]);
+
+        let lossless = Profile {
+            bitdepth: Some(24),
+            ..Profile::builtin("alac").unwrap()
+        };
+        assert_eq!(args(&lossless), ["-c:a", "alac", "-sample_fmt", "s32p"]);
+    }
+
+    #[test]
+    fn keeps_or_resizes_art() {
+        let mp3 = Profile::builtin("mp3-v0").unwrap();
+        assert_eq!(art_args(&mp3), ["-vn"]);
+
+        let resized = art_args(&Profile { art: Art::Resize(500), ..mp3.clone() });
+        assert_eq!(resized[..4], ["-map", "0:a:0", "-map", "0:v:0?"]);
+        assert_eq!(resized[7], "mjpeg");
+
+        let opus = Profile { art: Art::Keep, ..Profile::builtin("opus-128").unwrap() };
+        assert_eq!(art_args(&opus), ["-vn"]);
+
+        let parse = |s| toml::from_str::<Profile>(&format!("codec = \"mp3\"\nart = {}", s));
+        assert_eq!(parse("\"keep\"").unwrap().art, Art::Keep);
+        assert_eq!(parse("600").unwrap().art, Art::Resize(600));
+    }
+
+    #[test]
+    fn tags_or_applies_gain() {
+        let gain = Gain {
+            track: Loudness { integrated: -12.0, peak: 0.0 },
+            album: Some(Loudness { integrated: -14.0, peak: 0.0 }),
+        };
+        let mut profile = Profile {
+            replaygain: Some(GainMode::Album),
+            ..Profile::builtin("opus-128").unwrap()
+        };
+        let tagged = gain_args(&profile, &gain, false);
+        assert_eq!(tagged.len(), 8);
+        assert_eq!(tagged[1], "REPLAYGAIN_TRACK_GAIN=-6.00 dB");
+        assert_eq!(tagged[5], "REPLAYGAIN_ALBUM_GAIN=-4.00 dB");
+
+        profile.applygain = true;
+        let applied = gain_args(&profile, &gain, false);
+        assert_eq!(applied[..2], ["-filter:a", "volume=-4.00dB"]);
+        assert_eq!(applied[3], "REPLAYGAIN_TRACK_GAIN=");
+    }
+
+    #[test]
+    fn parses_ffprobe_output() {
+        let aac = parse_probe(
+            "codec_name=aac\nsample_rate=44100\nchannels=2\nbit_rate=256000\nbit_rate=261000\n",
+        );
+        assert_eq!(aac.bitrate, Some(256));
+        assert_eq!((aac.samplerate, aac.channels), (Some(44100), Some(2)));
+
+        // Lossless streams report no bitrate of their own; the container's is
+        // used instead.
+        let flac =
+            parse_probe("codec_name=flac\nbit_rate=N/A\nbits_per_raw_sample=24\nbit_rate=2116000\n");
+        assert_eq!((flac.bitrate, flac.bitdepth), (Some(2116), Some(24)));
+    }
+}
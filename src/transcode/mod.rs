@@ -0,0 +1,589 @@
+//! Converting library files for targets that can't take them as they are.
+//!
+//! A [`Profile`] says what a target wants; a [`Transcoder`] turns sources into
+//! that, through one of the [`Encoder`] backends, and caches the results.
+
+mod ffmpeg;
+mod native;
+mod tags;
+
+use crate::cache;
+use crate::error::Error;
+use crate::loudness::{Gain, GainMode, Loudness, LoudnessCache};
+use crate::state::Fingerprint;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use swinsiandb::Track;
+
+pub use ffmpeg::Ffmpeg;
+pub use native::Native;
+pub use tags::Tags;
+
+/// The audio codecs a [`Profile`] can encode to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Aac,
+    Alac,
+    Flac,
+    Mp3,
+    Opus,
+    Vorbis,
+    /// Uncompressed PCM in a WAV file.
+    Wav,
+}
+
+impl Codec {
+    /// Whether a stream reported as `name` (by ffprobe or symphonia) is in
+    /// this codec.
+    fn matches(self, name: &str) -> bool {
+        match self {
+            Codec::Aac => name == "aac",
+            Codec::Alac => name == "alac",
+            Codec::Flac => name == "flac",
+            Codec::Mp3 => name == "mp3",
+            Codec::Opus => name == "opus",
+            Codec::Vorbis => name == "vorbis",
+            Codec::Wav => name.starts_with("pcm_s"),
+        }
+    }
+
+    /// Whether files in this codec's usual containers can carry cover art.
+    fn embeds_art(self) -> bool {
+        matches!(self, Codec::Aac | Codec::Alac | Codec::Flac | Codec::Mp3)
+    }
+
+    fn default_extension(self) -> &'static str {
+        match self {
+            Codec::Aac | Codec::Alac => "m4a",
+            Codec::Flac => "flac",
+            Codec::Mp3 => "mp3",
+            Codec::Opus => "opus",
+            Codec::Vorbis => "ogg",
+            Codec::Wav => "wav",
+        }
+    }
+}
+
+/// Which [`Encoder`] a profile is converted with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// ffmpeg when it's installed, the native encoder otherwise.
+    #[default]
+    Auto,
+    Ffmpeg,
+    Native,
+}
+
+impl Backend {
+    /// The encoder this choice resolves to on this machine.
+    pub fn encoder(self) -> Box<dyn Encoder> {
+        match self {
+            Backend::Ffmpeg => Box::new(Ffmpeg),
+            Backend::Native => Box::new(Native),
+            Backend::Auto if ffmpeg::available() => Box::new(Ffmpeg),
+            Backend::Auto => Box::new(Native),
+        }
+    }
+}
+
+/// What happens to cover art embedded in a source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Art {
+    /// Dropped, keeping transcodes small.
+    #[default]
+    Strip,
+    /// Carried over as it is.
+    Keep,
+    /// Re-encoded as a JPEG no larger than this many pixels either way.
+    #[serde(untagged)]
+    Resize(u32),
+}
+
+/// Where the tags of a transcode come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metadata {
+    /// The song's record in Swinsian, with anything it has no value for
+    /// taken from the file.
+    #[default]
+    Swinsian,
+    /// Only the source file's own tags.
+    Source,
+}
+
+/// A named set of encoder settings, declared as a `[profile.<name>]` table
+/// and referenced from a target's `profile` key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub codec: Codec,
+    /// Constant/average bitrate in kbit/s.
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    /// VBR quality, passed to the encoder as `-q:a`; its scale depends on the
+    /// codec (e.g. 0 is LAME's V0).
+    #[serde(default)]
+    pub quality: Option<f32>,
+    #[serde(default)]
+    pub samplerate: Option<u32>,
+    /// Bits per sample; only meaningful for lossless codecs.
+    #[serde(default)]
+    pub bitdepth: Option<u32>,
+    #[serde(default)]
+    pub channels: Option<u32>,
+    /// File extension, which also picks the container. Defaults to the
+    /// codec's usual one.
+    #[serde(default)]
+    pub extension: Option<String>,
+    #[serde(default)]
+    pub backend: Backend,
+    /// Embedded cover art: `"strip"`, `"keep"` or a size in pixels to shrink
+    /// it to. Opus, Vorbis and WAV transcodes never carry art.
+    #[serde(default)]
+    pub art: Art,
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// Evens out loudness between songs, or between albums, by writing
+    /// ReplayGain tags.
+    #[serde(default)]
+    pub replaygain: Option<GainMode>,
+    /// Applies the `replaygain` adjustment to the audio itself instead, for
+    /// players that ignore the tags. Always re-encodes.
+    #[serde(default)]
+    pub applygain: bool,
+}
+
+impl Profile {
+    /// The profiles available without declaring them in the config.
+    pub fn builtin(name: &str) -> Option<Profile> {
+        let profile = |codec, bitrate, quality, samplerate, extension: Option<&str>| Profile {
+            codec,
+            bitrate,
+            quality,
+            samplerate,
+            bitdepth: None,
+            channels: None,
+            extension: extension.map(String::from),
+            backend: Backend::Auto,
+            art: Art::Strip,
+            metadata: Metadata::Swinsian,
+            replaygain: None,
+            applygain: false,
+        };
+        match name {
+            // What the watch has always been fed.
+            "aac-256k" => Some(profile(Codec::Aac, Some(256), None, Some(44100), Some("mp4"))),
+            "opus-128" => Some(profile(Codec::Opus, Some(128), None, None, None)),
+            "mp3-v0" => Some(profile(Codec::Mp3, None, Some(0.0), None, None)),
+            "alac" => Some(profile(Codec::Alac, None, None, None, None)),
+            "wav" => Some(profile(Codec::Wav, None, None, None, None)),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        self.extension
+            .as_deref()
+            .unwrap_or_else(|| self.codec.default_extension())
+    }
+
+    /// Whether a source with the `probed` properties can be used without
+    /// re-encoding: same codec and no higher bitrate, channel count or bit
+    /// depth than the profile asks for, at the profile's sample rate. A
+    /// bitrate within 5% of the profile's counts as a match.
+    fn satisfied_by(&self, probed: &Probe) -> bool {
+        fn within(limit: Option<u32>, actual: Option<u32>) -> bool {
+            match (limit, actual) {
+                (None, _) => true,
+                (Some(limit), Some(actual)) => actual <= limit,
+                (Some(_), None) => false,
+            }
+        }
+
+        self.codec.matches(&probed.codec)
+            && within(self.bitrate.map(|b| b + b / 20), probed.bitrate)
+            && (self.samplerate.is_none() || self.samplerate == probed.samplerate)
+            && within(self.channels, probed.channels)
+            && within(self.bitdepth, probed.bitdepth)
+    }
+}
+
+/// A way of inspecting and converting audio files.
+pub trait Encoder: Send + Sync {
+    /// Identifies the backend in logs, errors and cache keys.
+    fn name(&self) -> &'static str;
+
+    /// Whether this backend can produce files for `profile`.
+    fn supports(&self, profile: &Profile) -> bool;
+
+    /// Reads the properties of `file`'s first audio stream.
+    fn probe(&self, file: &Path) -> Result<Probe, Error>;
+
+    /// Measures the integrated loudness and peak of `file`.
+    fn analyse(&self, file: &Path) -> Result<Loudness, Error>;
+
+    /// Writes `file` to `output` as `profile` asks. With `remux` the source's
+    /// audio already matches and only needs a new container. `song` carries
+    /// the library's tags and the loudness adjustment, which is tagged, or
+    /// applied to the audio with `applygain` (never when remuxing).
+    fn encode(
+        &self,
+        file: &Path,
+        output: &Path,
+        profile: &Profile,
+        remux: bool,
+        song: &Song,
+    ) -> Result<(), Error>;
+}
+
+/// What a [`Transcoder`] knows about a source beyond the file itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Song {
+    /// The loudness adjustment, when the profile asks for ReplayGain.
+    pub gain: Option<Gain>,
+    /// The library's tags, when the profile writes them.
+    pub tags: Option<Tags>,
+}
+
+static UNKNOWN_SONG: Song = Song {
+    gain: None,
+    tags: None,
+};
+
+/// What a [`Transcoder`] does with a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Re-encode it with the profile.
+    Encode,
+    /// The audio already matches; only the container changes.
+    Remux,
+    /// The file already matches and is used as it is.
+    Passthrough,
+}
+
+/// The properties of a source's first audio stream.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Probe {
+    codec: String,
+    /// In kbit/s.
+    bitrate: Option<u32>,
+    samplerate: Option<u32>,
+    channels: Option<u32>,
+    bitdepth: Option<u32>,
+}
+
+/// Whether files with these extensions share a container format.
+fn same_container(a: &str, b: &str) -> bool {
+    const MP4: [&str; 3] = ["m4a", "mp4", "m4b"];
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    a == b || (MP4.contains(&a.as_str()) && MP4.contains(&b.as_str()))
+}
+
+/// Transcodes library files with one profile into a content-addressed cache.
+///
+/// Each transcode is stored under a key derived from the source's path, size
+/// and modification time plus the profile's encoder settings and what's known
+/// of the song (its library tags and loudness adjustment), so songs that
+/// share a file name never share a transcode, and a changed source, profile
+/// or tag simply misses the cache.
+pub struct Transcoder {
+    name: String,
+    cache_folder: PathBuf,
+    basepath: PathBuf,
+    profile: Profile,
+    encoder: Box<dyn Encoder>,
+    /// What's known about each song, by source path.
+    songs: HashMap<String, Song>,
+}
+
+impl Transcoder {
+    /// Caches transcodes in the [`TRANSCODES`](cache::TRANSCODES) folder
+    /// inside `cache_folder`; `name` is the profile's name.
+    pub fn new(
+        cache_folder: impl AsRef<Path>,
+        basepath: impl Into<PathBuf>,
+        name: &str,
+        profile: Profile,
+    ) -> Self {
+        Transcoder {
+            name: name.to_string(),
+            cache_folder: cache_folder.as_ref().join(cache::TRANSCODES),
+            basepath: basepath.into(),
+            encoder: profile.backend.encoder(),
+            profile,
+            songs: HashMap::new(),
+        }
+    }
+
+    /// Fails if the chosen backend can't produce this profile, e.g. MP3 on a
+    /// machine without ffmpeg.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.encoder.supports(&self.profile) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedProfile {
+                profile: self.name.clone(),
+                backend: self.encoder.name(),
+            })
+        }
+    }
+
+    /// The profile's name, as recorded in the sync state.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How the profile evens out loudness, if it does.
+    pub fn replaygain(&self) -> Option<GainMode> {
+        self.profile.replaygain
+    }
+
+    /// Works out the loudness adjustment of each of `tracks`, measuring
+    /// whatever `loudness` doesn't know yet. Album gains are computed over the
+    /// album's songs among `tracks`.
+    pub fn measure(&mut self, loudness: &mut LoudnessCache, tracks: &[&Track]) {
+        let sources: Vec<&str> = tracks.iter().map(|t| t.path.as_str()).collect();
+        loudness.analyse(self.encoder.as_ref(), &sources);
+        self.adjust(loudness, tracks);
+    }
+
+    /// Works out the loudness adjustment of each of `tracks` that `loudness`
+    /// already knows, without measuring anything.
+    pub fn adjust(&mut self, loudness: &LoudnessCache, tracks: &[&Track]) {
+        for (path, gain) in loudness.gains(tracks) {
+            self.songs.entry(path).or_default().gain = Some(gain);
+        }
+    }
+
+    /// Takes the tags of `tracks` from the library, when the profile wants
+    /// them written.
+    pub fn describe(&mut self, tracks: &[&Track]) {
+        if self.profile.metadata != Metadata::Swinsian {
+            return;
+        }
+        for track in tracks {
+            let song = self.songs.entry(track.path.clone()).or_default();
+            song.tags = Some(Tags::from_track(track));
+        }
+    }
+
+    /// The extension transcoded files get.
+    pub fn extension(&self) -> &str {
+        self.profile.extension()
+    }
+
+    /// Where `file`'s transcode goes on a destination: its path below the
+    /// basepath with the profile's extension.
+    pub fn relative_output(&self, file: &Path) -> Result<PathBuf, Error> {
+        let mut relative = pathdiff::diff_paths(file, &self.basepath)
+            .filter(|p| !p.starts_with("..") && p.file_stem().is_some())
+            .ok_or_else(|| {
+                Error::TranscodeCouldNotGenerateOutputFilename(file.to_string_lossy().into_owned())
+            })?;
+        relative.set_extension(self.profile.extension());
+        Ok(relative)
+    }
+
+    /// Works out whether `file` needs encoding, or already matches the profile
+    /// closely enough to be remuxed or used as it is. Files that can't be
+    /// probed are encoded.
+    pub fn decide(&self, file: &Path) -> Decision {
+        let probed = match self.encoder.probe(file) {
+            Ok(probed) => probed,
+            Err(e) => {
+                warn!("could not probe {}, encoding it: {}", file.display(), e);
+                return Decision::Encode;
+            }
+        };
+        let song = self.song(file);
+        if !self.profile.satisfied_by(&probed) || (song.gain.is_some() && self.profile.applygain)
+        {
+            return Decision::Encode;
+        }
+        // Tags and resized art can only be added by rewriting the container.
+        let extension = file.extension().unwrap_or_default().to_string_lossy();
+        let rewrite = song != &UNKNOWN_SONG || matches!(self.profile.art, Art::Resize(_));
+        if same_container(&extension, self.profile.extension()) && !rewrite {
+            Decision::Passthrough
+        } else {
+            Decision::Remux
+        }
+    }
+
+    fn song(&self, file: &Path) -> &Song {
+        file.to_str()
+            .and_then(|f| self.songs.get(f))
+            .unwrap_or(&UNKNOWN_SONG)
+    }
+
+    /// The cache key for `file` as it currently is on disk.
+    fn key(&self, file: &Path) -> Result<String, Error> {
+        let fp = Fingerprint::of(file)?;
+        let mut hasher = Sha3_256::new();
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update(format!("\0{}\0{}", fp.size, fp.mtime));
+        hasher.update(format!("\0{}\0", self.encoder.name()));
+        hasher.update(serde_json::to_vec(&self.profile)?);
+        let song = self.song(file);
+        if song != &UNKNOWN_SONG {
+            hasher.update(format!("\0{:?}", song));
+        }
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Where the transcode of `file` is stored in the cache folder.
+    fn output_path(&self, file: &Path) -> Result<PathBuf, Error> {
+        let key = self.key(file)?;
+        Ok(self
+            .cache_folder
+            .join(&key[..2])
+            .join(key)
+            .with_extension(self.profile.extension()))
+    }
+
+    /// Returns the cached transcode of `file`, if there is one.
+    pub fn cached(&self, file: &Path) -> Option<PathBuf> {
+        self.output_path(file).ok().filter(|p| p.exists())
+    }
+
+    /// Drops the cached transcode of `file`, which the cache can't tell is
+    /// stale when the file's contents changed but its size and modification
+    /// time didn't.
+    pub fn forget(&self, file: &Path) -> Result<(), Error> {
+        match std::fs::remove_file(self.output_path(file)?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Transcodes `file` with the profile into the cache folder, returning the
+    /// path of the transcoded file. Already-cached files are marked as used and
+    /// returned without re-encoding.
+    pub fn transcode(&self, file: &Path) -> Result<PathBuf, Error> {
+        let output = self.output_path(file)?;
+        if output.exists() {
+            cache::touch(&output)?;
+            return Ok(output);
+        }
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Write under a temporary name so an interrupted run never leaves a
+        // truncated file behind that looks like a cache hit.
+        let partial = output.with_extension(format!("partial.{}", self.profile.extension()));
+        let decision = self.decide(file);
+        info!("{:?} ({}): {}", decision, self.encoder.name(), file.display());
+        let result = match decision {
+            Decision::Passthrough => std::fs::copy(file, &partial).map(|_| ()).map_err(Error::from),
+            Decision::Remux => self.encode(file, &partial, true),
+            Decision::Encode => self.encode(file, &partial, false),
+        };
+
+        match result {
+            Ok(()) => {
+                std::fs::rename(&partial, &output)?;
+                Ok(output)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    fn encode(&self, file: &Path, output: &Path, remux: bool) -> Result<(), Error> {
+        self.encoder
+            .encode(file, output, &self.profile, remux, self.song(file))
+    }
+
+    /// Lays the transcodes of `files` out in a fresh staging folder, each
+    /// under the destination path it's paired with, ready to be synced, and
+    /// returns the folder. Transcodes are hard-linked from the cache where
+    /// possible.
+    pub fn stage(&self, files: &[(PathBuf, String)]) -> Result<PathBuf, Error> {
+        let dir = self.cache_folder.join(cache::STAGING).join(&self.name);
+        match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        for (file, destination) in files {
+            let transcoded = self.transcode(file)?;
+            let staged = dir.join(destination.trim_start_matches('/'));
+            if let Some(parent) = staged.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if std::fs::hard_link(&transcoded, &staged).is_err() {
+                std::fs::copy(&transcoded, &staged)?;
+            }
+        }
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_sources_to_destination_paths() {
+        let mp3 = Profile::builtin("mp3-v0").unwrap();
+        let transcoder = Transcoder::new("/cache", "/music", "mp3-v0", mp3);
+        assert_eq!(
+            transcoder.relative_output(Path::new("/music/A/01 B.flac")).unwrap(),
+            Path::new("A/01 B.mp3")
+        );
+    }
+
+    #[test]
+    fn passes_through_sources_that_match_the_profile() {
+        let probed = Probe {
+            codec: "aac".to_string(),
+            bitrate: Some(256),
+            samplerate: Some(44100),
+            channels: Some(2),
+            bitdepth: None,
+        };
+
+        let watch = Profile::builtin("aac-256k").unwrap();
+        assert!(watch.satisfied_by(&probed));
+        assert!(!watch.satisfied_by(&Probe { bitrate: Some(320), ..probed.clone() }));
+        assert!(!watch.satisfied_by(&Probe { samplerate: Some(48000), ..probed.clone() }));
+        assert!(!Profile::builtin("opus-128").unwrap().satisfied_by(&probed));
+
+        let pcm = Probe {
+            codec: "pcm_s16le".to_string(),
+            bitdepth: Some(16),
+            ..Probe::default()
+        };
+        assert!(Profile::builtin("wav").unwrap().satisfied_by(&pcm));
+
+        assert!(same_container("M4A", "mp4"));
+        assert!(!same_container("m4a", "opus"));
+    }
+
+    #[test]
+    fn keys_on_source_and_profile() {
+        let dir = std::env::temp_dir().join(format!("shittysync-key-{}", std::process::id()));
+        for album in ["A", "B"] {
+            std::fs::create_dir_all(dir.join(album)).unwrap();
+            std::fs::write(dir.join(album).join("01 Intro.flac"), "same").unwrap();
+        }
+
+        let aac = Transcoder::new("/cache", &dir, "aac", Profile::builtin("aac-256k").unwrap());
+        let opus = Transcoder::new("/cache", &dir, "opus", Profile::builtin("opus-128").unwrap());
+        let a = aac.key(&dir.join("A/01 Intro.flac")).unwrap();
+        assert_ne!(a, aac.key(&dir.join("B/01 Intro.flac")).unwrap());
+        assert_ne!(a, opus.key(&dir.join("A/01 Intro.flac")).unwrap());
+
+        std::fs::write(dir.join("A/01 Intro.flac"), "changed").unwrap();
+        assert_ne!(a, aac.key(&dir.join("A/01 Intro.flac")).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use super::{Codec, Encoder, Probe, Profile, Song};
+use crate::error::Error;
+use crate::loudness::{Loudness, Meter};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer};
+use symphonia::core::codecs::{CodecParameters, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Decodes with symphonia and writes WAV, all in-process, so targets can still
+/// be synced on machines without ffmpeg. It can't resample or remix, and
+/// metadata isn't carried over, so ReplayGain can only be applied, not tagged.
+pub struct Native;
+
+/// An opened source file and the audio track to decode from it.
+struct Source {
+    format: Box<dyn FormatReader>,
+    track_id: u32,
+    params: CodecParameters,
+}
+
+impl Source {
+    fn open(file: &Path) -> Result<Source, Error> {
+        let stream = MediaSourceStream::new(Box::new(File::open(file)?), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = file.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        let format = symphonia::default::get_probe()
+            .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+            .map_err(|e| decode_error(file, e))?
+            .format;
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| Error::Decode(file.to_string_lossy().into_owned(), "no audio".into()))?;
+        let (track_id, params) = (track.id, track.codec_params.clone());
+
+        Ok(Source {
+            format,
+            track_id,
+            params,
+        })
+    }
+
+    /// Decodes every packet of the audio track, handing each to `sink`. A
+    /// corrupt packet costs a moment of audio, not the whole file.
+    fn decode(
+        &mut self,
+        file: &Path,
+        mut sink: impl FnMut(AudioBufferRef) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&self.params, &DecoderOptions::default())
+            .map_err(|e| decode_error(file, e))?;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                Err(e) => return Err(decode_error(file, e)),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match decoder.decode(&packet) {
+                Ok(decoded) => sink(decoded)?,
+                Err(SymphoniaError::DecodeError(e)) => {
+                    warn!("{}: skipping undecodable packet: {}", file.display(), e);
+                }
+                Err(e) => return Err(decode_error(file, e)),
+            }
+        }
+    }
+}
+
+fn decode_error(file: &Path, e: SymphoniaError) -> Error {
+    Error::Decode(file.to_string_lossy().into_owned(), e.to_string())
+}
+
+impl Encoder for Native {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn supports(&self, profile: &Profile) -> bool {
+        profile.codec == Codec::Wav
+            && profile.samplerate.is_none()
+            && profile.channels.is_none()
+            && (profile.replaygain.is_none() || profile.applygain)
+    }
+
+    fn probe(&self, file: &Path) -> Result<Probe, Error> {
+        let params = Source::open(file)?.params;
+        let codec = symphonia::default::get_codecs()
+            .get_codec(params.codec)
+            .map(|d| d.short_name.to_string())
+            .unwrap_or_default();
+
+        // Symphonia doesn't report bitrates, so average over the whole file.
+        let seconds = match (params.n_frames, params.sample_rate) {
+            (Some(frames), Some(rate)) if rate > 0 => frames as f64 / rate as f64,
+            _ => 0.0,
+        };
+        let bits = std::fs::metadata(file)?.len() as f64 * 8.0;
+        let bitrate = (seconds > 0.0).then(|| (bits / seconds / 1000.0) as u32);
+
+        Ok(Probe {
+            codec,
+            bitrate,
+            samplerate: params.sample_rate,
+            channels: params.channels.map(|c| c.count() as u32),
+            bitdepth: params.bits_per_sample,
+        })
+    }
+
+    fn analyse(&self, file: &Path) -> Result<Loudness, Error> {
+        let mut source = Source::open(file)?;
+        let (Some(channels), Some(rate)) = (source.params.channels, source.params.sample_rate)
+        else {
+            let reason = "unknown channel layout or sample rate".to_string();
+            return Err(Error::Analyse(file.to_string_lossy().into_owned(), reason));
+        };
+
+        let mut meter = Meter::new(channels.count(), rate);
+        source.decode(file, |decoded| {
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            samples.copy_interleaved_ref(decoded);
+            meter.add(samples.samples());
+            Ok(())
+        })?;
+        meter.finish().ok_or_else(|| {
+            Error::Analyse(file.to_string_lossy().into_owned(), "no audible audio".into())
+        })
+    }
+
+    /// Decoding to PCM is lossless, so a remux is just another encode.
+    fn encode(
+        &self,
+        file: &Path,
+        output: &Path,
+        profile: &Profile,
+        _remux: bool,
+        song: &Song,
+    ) -> Result<(), Error> {
+        let mut source = Source::open(file)?;
+        let (Some(channels), Some(sample_rate)) = (source.params.channels, source.params.sample_rate)
+        else {
+            let reason = "unknown channel layout or sample rate".to_string();
+            return Err(Error::Decode(file.to_string_lossy().into_owned(), reason));
+        };
+        let bits = match profile.bitdepth.or(source.params.bits_per_sample) {
+            Some(bits) if bits > 24 => 32,
+            Some(bits) if bits > 16 => 24,
+            _ => 16,
+        };
+        let spec = WavSpec {
+            channels: channels.count() as u16,
+            sample_rate,
+            bits_per_sample: bits,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(output, spec)?;
+
+        let factor = match (song.gain, profile.replaygain) {
+            (Some(gain), Some(mode)) if profile.applygain => 10f64.powf(gain.db(mode) / 20.0),
+            _ => 1.0,
+        };
+        source.decode(file, |decoded| {
+            let mut samples = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+            samples.copy_interleaved_ref(decoded);
+            for &sample in samples.samples() {
+                let sample = if factor == 1.0 {
+                    sample
+                } else {
+                    (sample as f64 * factor).clamp(i32::MIN as f64, i32::MAX as f64) as i32
+                };
+                match bits {
+                    16 => writer.write_sample((sample >> 16) as i16)?,
+                    24 => writer.write_sample(sample >> 8)?,
+                    _ => writer.write_sample(sample)?,
+                }
+            }
+            Ok(())
+        })?;
+
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_and_reencodes_wav() {
+        let dir = std::env::temp_dir().join(format!("shittysync-native-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, output) = (dir.join("in.wav"), dir.join("out.wav"));
+
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&source, spec).unwrap();
+        for i in 0..44100i32 {
+            writer.write_sample((i % 2000 - 1000) as i16).unwrap();
+            writer.write_sample(-((i % 2000 - 1000) as i16)).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let probed = Native.probe(&source).unwrap();
+        assert_eq!(probed.codec, "pcm_s16le");
+        assert_eq!((probed.samplerate, probed.channels), (Some(44100), Some(2)));
+
+        let profile = Profile {
+            bitdepth: Some(24),
+            ..Profile::builtin("wav").unwrap()
+        };
+        assert!(Native.supports(&profile));
+        Native.encode(&source, &output, &profile, false, &Song::default()).unwrap();
+
+        let reader = hound::WavReader::open(&output).unwrap();
+        assert_eq!(reader.spec().bits_per_sample, 24);
+        assert_eq!(reader.duration(), 44100);
+
+        let loudness = Native.analyse(&source).unwrap();
+        assert!((loudness.peak - 20.0 * (1000.0f64 / 32768.0).log10()).abs() < 0.1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use swinsiandb::Track;
+
+/// A song's tags as curated in Swinsian, which can differ from what's in the
+/// file itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tags {
+    /// Values by ffmpeg's generic metadata key.
+    pub fields: Vec<(&'static str, String)>,
+    /// Star rating from 1 to 5; unrated songs have none.
+    pub rating: Option<u8>,
+}
+
+impl Tags {
+    /// The tags `track` has in the library. Fields Swinsian has no value for
+    /// are left out, so the file's own value survives.
+    pub fn from_track(track: &Track) -> Tags {
+        let mut fields = Vec::new();
+        let mut text = |key, value: &Option<String>| {
+            if let Some(value) = value.as_deref().filter(|v| !v.trim().is_empty()) {
+                fields.push((key, value.to_string()));
+            }
+        };
+        text("title", &track.title);
+        text("artist", &track.artist);
+        text("album", &track.album);
+        text("album_artist", &track.albumartist);
+        text("genre", &track.genre);
+        text("composer", &track.composer);
+        text("grouping", &track.grouping);
+        text("comment", &track.comment);
+        let numbers = [
+            ("date", track.year),
+            ("track", track.tracknumber),
+            ("disc", track.discnumber),
+        ];
+        for (key, value) in numbers {
+            if let Some(value) = value.filter(|v| *v > 0) {
+                fields.push((key, value.to_string()));
+            }
+        }
+
+        Tags {
+            fields,
+            // Swinsian stores ratings as a number of stars.
+            rating: track
+                .rating
+                .filter(|r| *r > 0)
+                .map(|r| r.min(5) as u8),
+        }
+    }
+
+    /// The rating as an `FMPS_RATING` value, from 0.0 to 1.0.
+    pub fn fmps_rating(&self) -> Option<String> {
+        self.rating.map(|stars| format!("{:.1}", f64::from(stars) / 5.0))
+    }
+
+    /// The rating as an ID3 POPM byte, on the scale Windows Media Player and
+    /// most taggers use.
+    pub fn popm_rating(&self) -> Option<u8> {
+        self.rating.map(|stars| match stars {
+            1 => 1,
+            2 => 64,
+            3 => 128,
+            4 => 196,
+            _ => 255,
+        })
+    }
+}
+
+/// Adds a POPM (popularimeter) frame carrying `rating` to the ID3v2 tag at
+/// the start of the MP3 file at `path`, creating the tag if there is none.
+/// ffmpeg can only write text frames, so this is done after encoding.
+pub fn write_popm(path: &Path, rating: u8) -> io::Result<()> {
+    let data = fs::read(path)?;
+
+    let (version, flags, tag_len) = match data.get(..10) {
+        Some(header) if header.starts_with(b"ID3") => {
+            (header[3], header[5], syncsafe_decode(&header[6..10]) as usize)
+        }
+        _ => (4, 0, 0),
+    };
+    // Extended headers and unsynchronisation would need rewriting the frames
+    // themselves; ffmpeg never writes either.
+    let truncated = tag_len > 0 && data.len() < 10 + tag_len;
+    if !(3..=4).contains(&version) || flags & 0xc0 != 0 || truncated {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported ID3v2 tag"));
+    }
+
+    // An empty e-mail address, the rating and no play counter.
+    let body = [0, rating];
+    let mut frame = b"POPM".to_vec();
+    if version == 4 {
+        frame.extend(syncsafe_encode(body.len() as u32));
+    } else {
+        frame.extend((body.len() as u32).to_be_bytes());
+    }
+    frame.extend([0, 0]);
+    frame.extend(body);
+
+    let (frames, audio) = if tag_len > 0 {
+        (&data[10..10 + tag_len], &data[10 + tag_len..])
+    } else {
+        (&data[..0], &data[..])
+    };
+    let mut out = Vec::with_capacity(data.len() + frame.len() + 10);
+    out.extend(b"ID3");
+    out.extend([version, 0, flags]);
+    out.extend(syncsafe_encode((frame.len() + frames.len()) as u32));
+    out.extend(&frame);
+    out.extend(frames);
+    out.extend(audio);
+    fs::write(path, out)
+}
+
+fn syncsafe_decode(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |n, b| (n << 7) | u32::from(b & 0x7f))
+}
+
+fn syncsafe_encode(n: u32) -> [u8; 4] {
+    [
+        (n >> 21) as u8 & 0x7f,
+        (n >> 14) as u8 & 0x7f,
+        (n >> 7) as u8 & 0x7f,
+        n as u8 & 0x7f,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_popm_to_existing_tag() {
+        let path = std::env::temp_dir().join(format!("shittysync-popm-{}.mp3", std::process::id()));
+        let title = b"TIT2\0\0\0\x04\0\0\x03Hey";
+        let mut file = b"ID3\x04\0\0".to_vec();
+        file.extend(syncsafe_encode(title.len() as u32));
+        file.extend(title);
+        file.extend(b"\xff\xfbaudio");
+        fs::write(&path, &file).unwrap();
+
+        write_popm(&path, 196).unwrap();
+        let written = fs::read(&path).unwrap();
+        assert_eq!(syncsafe_decode(&written[6..10]) as usize, title.len() + 12);
+        assert_eq!(&written[10..22], b"POPM\0\0\0\x02\0\0\0\xc4");
+        assert_eq!(&written[22..22 + title.len()], title);
+        assert!(written.ends_with(b"\xff\xfbaudio"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use crate::config::Watch as WatchConfig;
+use crate::config::MtpConfig;
 use crate::error::Error;
 use libmtp_rs::device::raw::detect_raw_devices;
 use libmtp_rs::device::MtpDevice;
@@ -7,15 +7,14 @@ use libmtp_rs::object::filetypes::Filetype;
 use libmtp_rs::object::AsObjectId;
 use libmtp_rs::storage::{files::FileMetadata, Parent, Storage};
 use sha3::{Digest, Sha3_256};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 pub struct Watch {
-    cfg: WatchConfig,
     device: MtpDevice,
 
-    /// Hashes of the files already present on the device.
-    map: Option<HashSet<String>>,
+    /// The files already present on the device, keyed by hash.
+    map: Option<HashMap<String, DeviceFile>>,
     music_folder: Option<Parent>,
 }
 
@@ -25,11 +24,20 @@ pub struct TransferObject {
     pub destination: PathBuf,
 }
 
+/// A file found on the device while indexing.
+#[derive(Debug, Clone)]
+pub struct DeviceFile {
+    pub name: String,
+    pub id: u32,
+    pub size: u64,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct WFile {
     name: String,
     id: u32,
+    size: u64,
     ftype: Filetype,
     children: Option<Vec<WFile>>,
 }
@@ -46,6 +54,7 @@ impl WFile {
                 WFile {
                     name: f.name().to_string(),
                     id: f.as_id(),
+                    size: f.size(),
                     ftype: f.ftype(),
                     children,
                 }
@@ -53,61 +62,70 @@ impl WFile {
             .collect()
     }
 
-    /// Flattens this node into the list of leaf (file) paths beneath `parent`.
-    pub fn resolve_recursive(&self, parent: PathBuf) -> Vec<PathBuf> {
+    /// Flattens this node into its leaf (file) nodes, each with its path
+    /// beneath `parent`.
+    pub fn resolve_recursive(&self, parent: PathBuf) -> Vec<(PathBuf, &WFile)> {
         match &self.children {
             Some(children) => children
                 .iter()
                 .flat_map(|f| f.resolve_recursive(parent.join(&self.name)))
                 .collect(),
-            None => vec![parent.join(&self.name)],
+            None => vec![(parent.join(&self.name), self)],
         }
     }
 }
 
 impl Watch {
-    pub async fn new(cfg: WatchConfig) -> Result<Watch, Error> {
+    pub async fn new(cfg: MtpConfig) -> Result<Watch, Error> {
         let raw_devices = detect_raw_devices()?;
 
         let mut device = raw_devices
             .into_iter()
             .filter_map(|raw| raw.open_uncached())
-            .find(|d| match d.get_friendly_name() {
-                Ok(name) => {
-                    println!("Found device {}", name);
-                    name == cfg.device_name
-                }
-                Err(_) => false,
+            .find(|d| {
+                let name = d.get_friendly_name().unwrap_or_default();
+                println!("Found device {}", name);
+                is_device(&cfg, &name, &d.get_serial_number().unwrap_or_default())
             })
-            .ok_or_else(|| Error::CouldNotFindWatch(cfg.device_name.clone()))?;
+            .ok_or_else(|| {
+                let wanted = cfg.serial.as_ref().unwrap_or(&cfg.device_name);
+                Error::CouldNotFindWatch(wanted.clone())
+            })?;
 
         device.update_storage(StorageSort::ByFreeSpace)?;
 
-        let mut watch = Watch {
-            cfg,
-            device,
-            map: None,
-            music_folder: None,
+        let music_folder = {
+            let storage_pool = device.storage_pool();
+            let (_, storage) = storage_pool.iter().next().ok_or(Error::NoWatchStorage)?;
+            Parent::Folder(Self::find_folder(storage, &cfg.base_folder)?)
         };
-        watch.build_index()?;
 
-        Ok(watch)
+        Ok(Watch {
+            device,
+            map: None,
+            music_folder: Some(music_folder),
+        })
     }
 
-    fn build_index(&mut self) -> Result<(), Error> {
+    /// Reads the files under the base folder into the index used by
+    /// [`Watch::exists`], [`Watch::unwanted`] and [`Watch::delete_file`].
+    /// Enumerating the device is slow, so this only happens on request.
+    pub fn build_index(&mut self) -> Result<(), Error> {
         let storage_pool = self.device.storage_pool();
         let (_, storage) = storage_pool.iter().next().ok_or(Error::NoWatchStorage)?;
-
-        let music_folder_id = Self::find_folder(storage, &self.cfg.base_folder)?;
-        let music_folder = Parent::Folder(music_folder_id);
-        self.music_folder = Some(music_folder);
+        let music_folder = self.music_folder.ok_or(Error::NoWatchStorage)?;
 
         let index = WFile::from_storage(storage, music_folder);
 
-        let mut map = HashSet::new();
+        let mut map = HashMap::new();
         for node in &index {
-            for path in node.resolve_recursive(PathBuf::new()) {
-                map.insert(strip_extension(&path));
+            for (path, file) in node.resolve_recursive(PathBuf::new()) {
+                let device_file = DeviceFile {
+                    name: path.to_string_lossy().into_owned(),
+                    id: file.id,
+                    size: file.size,
+                };
+                map.insert(strip_extension(&path), device_file);
             }
         }
         self.map = Some(map);
@@ -118,23 +136,70 @@ impl Watch {
     /// Returns whether a file for `p` (matched by its content hash) already
     /// exists on the device.
     pub fn exists(&self, p: &Path) -> bool {
-        match &self.map {
-            Some(map) => map.contains(&sha3_hex(&strip_extension(p))),
-            None => false,
-        }
+        self.file(p).is_some()
+    }
+
+    /// The file on the device for `p`, if the index has one.
+    pub fn file(&self, p: &Path) -> Option<&DeviceFile> {
+        self.map.as_ref()?.get(&file_hash(p))
+    }
+
+    /// Reads `file` back from the device into `to`.
+    pub fn read_file(&self, file: &DeviceFile, to: &Path) -> Result<(), Error> {
+        let storage_pool = self.device.storage_pool();
+        let (_, storage) = storage_pool.iter().next().ok_or(Error::NoWatchStorage)?;
+        storage.get_file_to_path(file.id, to)?;
+        Ok(())
     }
 
+    /// Returns the files on the device whose hash isn't in `keep`, sorted by
+    /// name.
+    pub fn unwanted(&self, keep: &HashSet<String>) -> Vec<&DeviceFile> {
+        let mut files: Vec<&DeviceFile> = self
+            .map
+            .iter()
+            .flatten()
+            .filter(|(hash, _)| !keep.contains(*hash))
+            .map(|(_, file)| file)
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        files
+    }
+
+    /// Deletes the file called `name` (as listed by [`Watch::unwanted`]) from
+    /// the device.
+    pub fn delete_file(&mut self, name: &str) -> Result<(), Error> {
+        let hash = strip_extension(Path::new(name));
+        let map = self.map.as_mut().ok_or(Error::NoWatchStorage)?;
+        let file = map
+            .get(&hash)
+            .ok_or_else(|| Error::CouldNotFindFile(name.to_string()))?;
+
+        self.device.delete_object(file.id)?;
+        map.remove(&hash);
+        Ok(())
+    }
+
+    /// Uploads a transcode, replacing the file already on the device for the
+    /// same destination, if the index has one.
     pub fn put_file(&mut self, t: TransferObject) -> Result<(), Error> {
         use libmtp_rs::util::CallbackReturn;
         use std::io::Write;
 
+        let hash = file_hash(&t.destination);
+        if let Some(old) = self.map.as_mut().and_then(|map| map.remove(&hash)) {
+            println!("replacing {}", old.name);
+            self.device.delete_object(old.id)?;
+        }
+
         let storage_pool = self.device.storage_pool();
         let (_, storage) = storage_pool.iter().next().ok_or(Error::NoWatchStorage)?;
 
         let file = std::fs::File::open(&t.transcoded)?;
         let file_metadata = file.metadata()?;
 
-        let file_name = format!("{}.mp4", sha3_hex(&strip_extension(&t.destination)));
+        let extension = t.destination.extension().unwrap_or_default();
+        let file_name = format!("{}.{}", hash, extension.to_string_lossy());
         let metadata = FileMetadata {
             file_size: file_metadata.len(),
             file_name: &file_name,
@@ -170,6 +235,36 @@ impl Watch {
     }
 }
 
+/// The friendly name and serial number of each MTP device plugged in.
+pub fn connected() -> Vec<(String, String)> {
+    // libmtp reports having found no devices as an error.
+    let Ok(raw_devices) = detect_raw_devices() else {
+        return Vec::new();
+    };
+    raw_devices
+        .into_iter()
+        .filter_map(|raw| raw.open_uncached())
+        .map(|d| {
+            let name = d.get_friendly_name().unwrap_or_default();
+            (name, d.get_serial_number().unwrap_or_default())
+        })
+        .collect()
+}
+
+/// Whether the device called `name` with the serial number `serial` is the
+/// one `cfg` syncs to.
+pub fn is_device(cfg: &MtpConfig, name: &str, serial: &str) -> bool {
+    match &cfg.serial {
+        Some(wanted) => wanted == serial,
+        None => cfg.device_name == name,
+    }
+}
+
+/// The hash a file for `destination` is stored under on the device.
+pub fn file_hash(destination: &Path) -> String {
+    sha3_hex(&strip_extension(destination))
+}
+
 /// Returns `path` without its extension as a lossy UTF-8 string.
 fn strip_extension(path: &Path) -> String {
     path.with_extension("").to_string_lossy().into_owned()